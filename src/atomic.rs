@@ -0,0 +1,40 @@
+//! Writing files without a window where they're truncated or half written:
+//! the contents go to a temporary file next to the target first, which is
+//! then renamed over it.
+
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// The temporary file `path` is written to before being renamed into place,
+/// in the same directory so the rename doesn't cross file systems.
+pub fn temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{name}.{}.tmp", std::process::id()))
+}
+
+/// Replaces `path` with `contents` in one step, keeping the permissions of
+/// the file it replaces. The directory it's in is created if it's missing.
+pub fn write(path: &Path, contents: impl AsRef<[u8]>) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let temp = temp_path(path);
+
+    let result = (|| {
+        let mut file = std::fs::File::create(&temp)?;
+        file.write_all(contents.as_ref())?;
+        file.sync_all()?;
+        if let Ok(metadata) = std::fs::metadata(path) {
+            std::fs::set_permissions(&temp, metadata.permissions())?;
+        }
+
+        std::fs::rename(&temp, path)
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+
+    result
+}
@@ -0,0 +1,88 @@
+use std::{collections::BTreeMap, path::Path, path::PathBuf, time::SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::Config,
+    file_format::parser::{File, Parse},
+};
+
+/// Todo counts of a file, kept in the cache directory so status bar commands
+/// don't have to parse the file on every refresh. Stale once the file's or
+/// the config's modification time changes, as the config decides how states
+/// are counted.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Summary {
+    modified: SystemTime,
+    #[serde(default)]
+    config_modified: Option<SystemTime>,
+    /// How many todos there are in each state, keyed by the printed state.
+    pub states: BTreeMap<String, usize>,
+    pub total: usize,
+    pub done: usize,
+}
+
+impl Summary {
+    pub fn new(config: &Config, parsed: &File, modified: SystemTime) -> Self {
+        let mut states = BTreeMap::new();
+        let mut done = 0;
+        for todo in parsed.headings().iter().flat_map(|heading| heading.todos()) {
+            *states.entry(todo.state.print(config)).or_insert(0) += 1;
+            if config.is_done(&todo.state) {
+                done += 1;
+            }
+        }
+
+        Self {
+            modified,
+            config_modified: modified_config(),
+            total: states.values().sum(),
+            states,
+            done,
+        }
+    }
+
+    /// The cached summary of `file`, as long as neither the file nor the
+    /// config have changed since.
+    pub fn load(file: &Path) -> Option<Self> {
+        let summary: Self =
+            serde_json::from_str(&std::fs::read_to_string(cache_path(file)?).ok()?).ok()?;
+
+        (Some(summary.modified) == modified(file) && summary.config_modified == modified_config())
+            .then_some(summary)
+    }
+
+    pub fn store(&self, file: &Path) {
+        if let Some(path) = cache_path(file) {
+            let _ = crate::atomic::write(&path, serde_json::to_string(self).unwrap());
+        }
+    }
+}
+
+pub fn modified(file: &Path) -> Option<SystemTime> {
+    std::fs::metadata(file)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+fn modified_config() -> Option<SystemTime> {
+    modified(&Config::path())
+}
+
+/// A name for `file` that's unique across the file system: its absolute path
+/// with the separators swapped out.
+pub fn file_key(file: &Path) -> Option<String> {
+    let path = std::fs::canonicalize(file)
+        .or_else(|_| std::path::absolute(file))
+        .ok()?;
+
+    Some(path.to_string_lossy().replace('%', "%%").replace('/', "%"))
+}
+
+/// Where the summary of `file` is cached, in `$XDG_CACHE_HOME/todo`.
+fn cache_path(file: &Path) -> Option<PathBuf> {
+    xdg::BaseDirectories::with_prefix("todo")
+        .ok()?
+        .place_cache_file(format!("{}.json", file_key(file)?))
+        .ok()
+}
@@ -1,26 +1,104 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
 
-use mlua::{Lua, Result as LuaResult, Table};
-use serde::{Serialize, Deserialize};
+use chrono::{Datelike, Locale, NaiveDate};
+use mlua::{Function, Lua, LuaSerdeExt, RegistryKey, Result as LuaResult, Table, Value};
+use regex::Regex;
+use serde::{de::DeserializeOwned, Serialize, Deserialize};
 use shellexpand::tilde;
 
+use crate::file_format::parser::TodoState;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
     pub template: Option<PathBuf>,
-    pub directory: PathBuf,
+    #[serde(skip)]
+    pub template_fn: Option<Rc<LuaFunction>>,
+    #[serde(skip)]
+    pub transform: Option<Rc<LuaFunction>>,
+    pub templates_dir: Option<PathBuf>,
+    pub directories: Vec<PathBuf>,
     pub editor: Option<String>,
+    pub post_write: Option<String>,
     pub bullet_point: Option<String>,
     pub todo_state_ops: Option<TodoStateOps>,
-    pub todo_state: HashMap<String, String>,
+    pub heading_style: Option<HeadingStyle>,
+    pub todo_state: BTreeMap<String, String>,
+    /// Other ways of writing a `todo_state` key, mapped to that key.
+    pub state_aliases: BTreeMap<String, String>,
+    pub done_states: Vec<String>,
+    pub done_marker: Option<String>,
+    pub state_cycle: Vec<String>,
+    pub state_order: Vec<String>,
+    pub colors: BTreeMap<String, String>,
+    pub heading_templates: BTreeMap<String, String>,
+    pub notes_heading: Option<String>,
+    pub empty_placeholder: Option<String>,
+    pub locale: Option<String>,
+    pub date_label_format: Option<String>,
+    pub body_indent: Option<usize>,
+    pub wrap_width: Option<usize>,
+    pub max_file_bytes: Option<u64>,
+    pub wrap_code: Option<bool>,
+    pub compact: Option<bool>,
+    pub state_width: Option<usize>,
+    pub tab_width: Option<usize>,
+    pub undo_depth: Option<usize>,
+    pub preserve_blank_lines: Option<bool>,
+    #[serde(skip)]
+    pub lenient: Option<bool>,
+    #[serde(skip)]
+    pub colored: Option<bool>,
+    #[serde(skip)]
+    pub highlight: Vec<Regex>,
 }
 
 impl Config {
+    /// The first configured directory, where new files are written.
+    pub fn directory(&self) -> &Path {
+        &self.directories[0]
+    }
+
+    /// Finds `name` in the first directory that has it, falling back to the
+    /// primary directory. Absolute paths are returned as they are.
+    pub fn resolve(&self, name: &Path) -> PathBuf {
+        self.directories
+            .iter()
+            .map(|directory| directory.join(name))
+            .find(|path| path.exists())
+            .unwrap_or_else(|| self.directory().join(name))
+    }
+
+    /// Where the config is read from: `$TODO_CONFIG` when it's set,
+    /// otherwise `todo/config.lua` in the XDG config directory, which is
+    /// created if it's missing.
+    pub fn path() -> PathBuf {
+        if let Ok(path) = std::env::var("TODO_CONFIG") {
+            PathBuf::from(tilde(&path).to_string())
+        } else {
+            xdg::BaseDirectories::with_prefix("todo")
+                .unwrap()
+                .place_config_file("config.lua")
+                .unwrap()
+        }
+    }
+
+    /// Loads the config from [`Config::path`], writing a default one there
+    /// if it's missing. `$TODO_CONFIG` has to point to an existing file.
     pub fn get() -> LuaResult<Self> {
-        let config = xdg::BaseDirectories::with_prefix("todo").unwrap();
-        let config_path = config.place_config_file("config.lua").unwrap();
+        let config_path = Self::path();
+        if std::env::var_os("TODO_CONFIG").is_some() && !config_path.exists() {
+            return Err(mlua::Error::RuntimeError(format!(
+                "TODO_CONFIG points to {}, which doesn't exist",
+                config_path.display()
+            )));
+        }
 
         if !config_path.exists() {
-            std::fs::write(
+            crate::atomic::write(
                 &config_path,
                 r#"return {
     directory = "~/todo",
@@ -29,47 +107,370 @@ impl Config {
             .unwrap();
         }
 
+        Self::load(&std::fs::read_to_string(&config_path).unwrap())
+    }
+
+    /// Evaluates `source`, a Lua chunk returning the config table.
+    pub fn load(source: &str) -> LuaResult<Self> {
         Ok({
-            let lua = Lua::new();
-            let mut config = Self::from_table(
-                lua.load(&std::fs::read_to_string(&config_path).unwrap())
-                    .eval::<Table>()?,
-            )?;
+            let lua = Rc::new(Lua::new());
+            let (mut config, template_fn, transform) = {
+                let table = lua.load(source).eval::<Table>()?;
+                let function = |key| -> LuaResult<_> {
+                    Ok(match table.get::<_, Value>(key)? {
+                        Value::Function(function) => Some(lua.create_registry_value(function)?),
+                        _ => None,
+                    })
+                };
+                let template_fn = function("template")?;
+                let transform = function("transform")?;
+
+                (Self::from_table(table)?, template_fn, transform)
+            };
+            let function = |key| Rc::new(LuaFunction { lua: lua.clone(), key });
+            config.template_fn = template_fn.map(function);
+            config.transform = transform.map(function);
             if let Some(template) = &mut config.template {
                 *template = PathBuf::from(tilde(template.to_str().unwrap()).to_string());
             }
-            config.directory = PathBuf::from(tilde(config.directory.to_str().unwrap()).to_string());
+            if let Some(templates_dir) = &mut config.templates_dir {
+                *templates_dir = PathBuf::from(tilde(templates_dir.to_str().unwrap()).to_string());
+            }
+            if config.directories.is_empty() {
+                return Err(mlua::Error::RuntimeError(
+                    "directory needs at least one path".to_owned(),
+                ));
+            }
+            for directory in &mut config.directories {
+                *directory = PathBuf::from(tilde(directory.to_str().unwrap()).to_string());
+            }
 
             config
         })
     }
 
     fn from_table(table: Table) -> LuaResult<Self> {
+        // a state maps either to how it's displayed, or to a list of aliases
+        // with the display in `display`, defaulting to the state itself
+        let mut todo_state = BTreeMap::new();
+        let mut state_aliases = BTreeMap::new();
+        if let Some(states) = table.get::<_, Option<Table>>("todo_state")? {
+            for pair in states.pairs::<String, Value>() {
+                let Ok((state, value)) = pair else {
+                    continue;
+                };
+                let display = match value {
+                    Value::String(display) => display.to_str()?.to_owned(),
+                    Value::Table(aliases) => {
+                        for alias in aliases.clone().sequence_values::<String>() {
+                            state_aliases.insert(alias?, state.clone());
+                        }
+                        aliases
+                            .get::<_, Option<String>>("display")?
+                            .unwrap_or_else(|| state.clone())
+                    }
+                    _ => continue,
+                };
+                todo_state.insert(state, display);
+            }
+        }
+
         Ok(Self {
             template: table
                 .get::<_, String>("template")
                 .ok()
                 .map(|template| PathBuf::from(template)),
-            directory: PathBuf::from(table.get::<_, String>("directory")?),
+            template_fn: None,
+            transform: None,
+            templates_dir: table
+                .get::<_, Option<String>>("templates_dir")?
+                .map(PathBuf::from),
+            directories: match table.get::<_, Value>("directory")? {
+                Value::Table(directories) => directories
+                    .sequence_values::<String>()
+                    .map(|directory| directory.map(PathBuf::from))
+                    .collect::<LuaResult<_>>()?,
+                _ => vec![PathBuf::from(table.get::<_, String>("directory")?)],
+            },
             editor: table.get("editor").ok(),
+            post_write: table.get("post_write").ok(),
             bullet_point: table.get("bullet_point").ok(),
             todo_state_ops: if let Some(table) = table.get::<_, Table>("todo_state_ops").ok() {
                 Some(TodoStateOps::from_table(table)?)
             } else {
                 None
             },
-            todo_state: if let Some(table) = table.get::<_, Option<Table>>("todo_state")? {
-                HashMap::from_iter(
-                    table
-                        .pairs::<String, String>()
-                        .into_iter()
-                        .filter_map(|pair| pair.ok()),
-                )
+            heading_style: if let Some(table) = table.get::<_, Option<Table>>("heading_style")? {
+                Some(HeadingStyle::from_table(table)?)
             } else {
-                HashMap::new()
+                None
             },
+            todo_state,
+            state_aliases,
+            done_states: table
+                .get::<_, Option<Vec<String>>>("done_states")?
+                .unwrap_or_else(|| vec!["x".to_owned()]),
+            done_marker: table.get("done_marker").ok(),
+            state_cycle: table
+                .get::<_, Option<Vec<String>>>("state_cycle")?
+                .unwrap_or_default(),
+            state_order: table
+                .get::<_, Option<Vec<String>>>("state_order")?
+                .unwrap_or_default(),
+            colors: table
+                .get::<_, Option<BTreeMap<String, String>>>("colors")?
+                .unwrap_or_default(),
+            heading_templates: table
+                .get::<_, Option<BTreeMap<String, String>>>("heading_templates")?
+                .unwrap_or_default(),
+            notes_heading: table.get("notes_heading").ok(),
+            empty_placeholder: table.get("empty_placeholder").ok(),
+            locale: table.get("locale").ok(),
+            date_label_format: table.get("date_label_format").ok(),
+            body_indent: table.get("body_indent").ok(),
+            wrap_width: table.get("wrap_width").ok(),
+            max_file_bytes: table.get("max_file_bytes").ok(),
+            wrap_code: table.get::<_, Option<bool>>("wrap_code")?,
+            compact: table.get::<_, Option<bool>>("compact")?,
+            state_width: table.get("state_width").ok(),
+            tab_width: table.get("tab_width").ok(),
+            undo_depth: table.get("undo_depth").ok(),
+            preserve_blank_lines: table.get::<_, Option<bool>>("preserve_blank_lines")?,
+            lenient: None,
+            colored: None,
+            highlight: vec![],
         })
     }
+
+    /// How far todos, bullets and text are indented under their heading.
+    pub fn body_indent(&self) -> usize {
+        self.body_indent.unwrap_or(4)
+    }
+
+    /// Files bigger than this aren't read. Defaults to 4 MiB.
+    pub fn max_file_bytes(&self) -> u64 {
+        self.max_file_bytes.unwrap_or(4 * 1024 * 1024)
+    }
+
+    /// The column text is wrapped at, `None` when wrapping is disabled
+    /// (`wrap_width = 0`). Defaults to the terminal width.
+    pub fn wrap_width(&self) -> Option<usize> {
+        match self.wrap_width {
+            Some(0) => None,
+            Some(width) => Some(width),
+            None => Some(textwrap::termwidth()),
+        }
+    }
+
+    /// Whether verbatim spans may be broken across lines. Defaults to true.
+    pub fn wrap_code(&self) -> bool {
+        self.wrap_code.unwrap_or(true)
+    }
+
+    /// Whether to leave out the blank lines between headings and cut long
+    /// text off at the wrap width instead of wrapping it.
+    pub fn compact(&self) -> bool {
+        self.compact.unwrap_or(false)
+    }
+
+    /// The heading notes are appended under. Defaults to `Notes`.
+    pub fn notes_heading(&self) -> &str {
+        self.notes_heading.as_deref().unwrap_or("Notes")
+    }
+
+    /// What `show` prints when there's nothing to show. Defaults to
+    /// `no todos`, an empty string prints nothing.
+    pub fn empty_placeholder(&self) -> &str {
+        self.empty_placeholder.as_deref().unwrap_or("no todos")
+    }
+
+    /// How many earlier versions of a file `undo` can go back to. Defaults
+    /// to 10.
+    pub fn undo_depth(&self) -> usize {
+        self.undo_depth.unwrap_or(10)
+    }
+
+    /// Whether runs of blank lines after a heading's block are written back
+    /// as they were, instead of collapsed into one. Defaults to collapsing.
+    pub fn preserve_blank_lines(&self) -> bool {
+        self.preserve_blank_lines.unwrap_or(false)
+    }
+
+    /// Whether a line that fails to parse is kept as an error in its place
+    /// instead of failing the whole file. Only `show --lenient` sets it, as
+    /// the failed lines can't be written back.
+    pub fn lenient(&self) -> bool {
+        self.lenient.unwrap_or(false)
+    }
+
+    /// Whether to style the output with ANSI escapes, as decided by `--color`.
+    pub fn colored(&self) -> bool {
+        self.colored.unwrap_or(true)
+    }
+
+    /// How many spaces a tab in text is shown as. Defaults to 4.
+    pub fn tab_width(&self) -> usize {
+        self.tab_width.unwrap_or(4)
+    }
+
+    /// Formats a day header with `date_label_format` (default `%A %Y-%m-%d`),
+    /// using `locale` (e.g. `de_DE`) for weekday and month names when it's a
+    /// known locale.
+    pub fn date_label(&self, date: NaiveDate) -> String {
+        let format = self.date_label_format.as_deref().unwrap_or("%A %Y-%m-%d");
+
+        match self
+            .locale
+            .as_deref()
+            .and_then(|locale| Locale::try_from(locale).ok())
+        {
+            Some(locale) => date.format_localized(format, locale).to_string(),
+            None => date.format(format).to_string(),
+        }
+    }
+
+    /// Resolves a color name through the `colors` palette, falling back to
+    /// `#rgb`/`#rrggbb` hex codes and basic CSS color names.
+    pub fn color(&self, name: &str) -> Option<(u8, u8, u8)> {
+        let name = self.colors.get(name).map_or(name, String::as_str);
+
+        if let Some(hex) = name.strip_prefix('#') {
+            let channel = |i: usize, len: usize| {
+                u8::from_str_radix(hex.get(i * len..(i + 1) * len)?, 16)
+                    .ok()
+                    .map(|c| if len == 1 { c * 17 } else { c })
+            };
+
+            return match hex.len() {
+                3 => Some((channel(0, 1)?, channel(1, 1)?, channel(2, 1)?)),
+                6 => Some((channel(0, 2)?, channel(1, 2)?, channel(2, 2)?)),
+                _ => None,
+            };
+        }
+
+        CSS_COLORS
+            .iter()
+            .find(|(css, _)| css.eq_ignore_ascii_case(name))
+            .map(|(_, rgb)| *rgb)
+    }
+
+    /// The state written when marking a todo done, defaulting to the first
+    /// of `done_states`.
+    pub fn done_marker(&self) -> &str {
+        self.done_marker
+            .as_deref()
+            .or(self.done_states.first().map(String::as_str))
+            .unwrap_or("x")
+    }
+
+    /// How `state` is displayed, the state itself when it isn't a
+    /// `todo_state` key.
+    fn display<'a>(&'a self, state: &'a str) -> &'a str {
+        self.todo_state.get(state).map_or(state, String::as_str)
+    }
+
+    /// Whether the state is one of `done_states`, or is displayed the same
+    /// as one of them.
+    pub fn is_done(&self, state: &TodoState) -> bool {
+        let state = self.display(state.as_str());
+
+        self.done_states
+            .iter()
+            .any(|done| self.display(done) == state)
+    }
+
+    /// Where the state goes in `state_order`, most actionable first. States
+    /// that aren't listed all rank after the listed ones.
+    pub fn state_rank(&self, state: &TodoState) -> usize {
+        let state = self.display(state.as_str());
+
+        self.state_order
+            .iter()
+            .position(|listed| self.display(listed) == state)
+            .unwrap_or(self.state_order.len())
+    }
+}
+
+/// The commented config `todo init` writes.
+pub const STARTER_CONFIG: &str = r##"-- Everything but `directory` is optional.
+return {
+    -- Where todo files are kept. Several directories can be given as a
+    -- list, new files go into the first one.
+    directory = "~/todo",
+
+    -- What a new day's file starts as, either a file or a function given
+    -- { year, month, day, weekday } that returns the contents.
+    -- template = "~/todo/template.todo",
+    -- template = function(date)
+    --     return "# " .. date.weekday .. "\n"
+    -- end,
+
+    -- The command `todo edit` opens files with, $EDITOR when it's not set.
+    -- editor = "nvim",
+
+    -- How the states inside `[...]` are shown. A list maps other ways of
+    -- writing a state to it.
+    -- todo_state = {
+    --     x = { "done", "✓", display = "✓" },
+    --     ["/"] = "…",
+    -- },
+}
+"##;
+
+const CSS_COLORS: [(&str, (u8, u8, u8)); 17] = [
+    ("black", (0, 0, 0)),
+    ("white", (255, 255, 255)),
+    ("gray", (128, 128, 128)),
+    ("grey", (128, 128, 128)),
+    ("silver", (192, 192, 192)),
+    ("red", (255, 0, 0)),
+    ("maroon", (128, 0, 0)),
+    ("orange", (255, 165, 0)),
+    ("yellow", (255, 255, 0)),
+    ("lime", (0, 255, 0)),
+    ("green", (0, 128, 0)),
+    ("cyan", (0, 255, 255)),
+    ("teal", (0, 128, 128)),
+    ("blue", (0, 0, 255)),
+    ("navy", (0, 0, 128)),
+    ("magenta", (255, 0, 255)),
+    ("purple", (128, 0, 128)),
+];
+
+/// A function from the config, such as `template` or `transform`, kept
+/// alongside the Lua state it lives in.
+#[derive(Debug)]
+pub struct LuaFunction {
+    lua: Rc<Lua>,
+    key: RegistryKey,
+}
+
+impl LuaFunction {
+    /// Calls the function with a `{ year, month, day, weekday }` table for
+    /// `date` (`weekday` being e.g. `"Monday"`) and returns the template it
+    /// builds.
+    pub fn render(&self, date: NaiveDate) -> LuaResult<String> {
+        let table = self.lua.create_table()?;
+        table.set("year", date.year())?;
+        table.set("month", date.month())?;
+        table.set("day", date.day())?;
+        table.set("weekday", date.format("%A").to_string())?;
+
+        self.lua
+            .registry_value::<Function>(&self.key)?
+            .call(table)
+    }
+
+    /// Hands `value` to the function as a table and reads what it returns
+    /// back as the same type, failing when it doesn't have that shape.
+    pub fn transform<T: Serialize + DeserializeOwned>(&self, value: &T) -> LuaResult<T> {
+        let transformed = self
+            .lua
+            .registry_value::<Function>(&self.key)?
+            .call::<_, Value>(self.lua.to_value(value)?)?;
+
+        self.lua.from_value(transformed)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -86,3 +487,49 @@ impl TodoStateOps {
         })
     }
 }
+
+/// How `show` prints heading titles: a `prefix` glyph before them, in
+/// `uppercase` and in a `color`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HeadingStyle {
+    pub prefix: Option<String>,
+    pub uppercase: bool,
+    pub color: Option<String>,
+}
+
+impl HeadingStyle {
+    fn from_table(table: Table) -> LuaResult<Self> {
+        Ok(Self {
+            prefix: table.get("prefix")?,
+            uppercase: table.get::<_, Option<bool>>("uppercase")?.unwrap_or(false),
+            color: table.get("color")?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = r##"return {
+        directory = "/tmp",
+        todo_state = { x = "done", ["/"] = "doing", a = "a", z = { "zz", "zzz" } },
+        colors = { red = "#ff0000", blue = "#0000ff", green = "#00ff00" },
+        heading_templates = { Work = "[ ] standup", Home = "[ ] dishes" },
+    }"##;
+
+    #[test]
+    fn serializes_the_same_every_time() {
+        let first = serde_json::to_string(&Config::load(SOURCE).unwrap()).unwrap();
+        let second = serde_json::to_string(&Config::load(SOURCE).unwrap()).unwrap();
+        assert_eq!(first, second);
+
+        let todo_state = serde_json::to_value(Config::load(SOURCE).unwrap()).unwrap()["todo_state"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        assert_eq!(todo_state, ["/", "a", "x", "z"]);
+    }
+}
@@ -7,70 +7,240 @@ use super::parser::{Parse, TextOp, Todo};
 #[derive(Debug, Serialize, Deserialize)]
 pub struct EwwTodo {
     state: String,
+    state_class: String,
     description: Vec<String>,
+    contexts: Vec<String>,
 }
 
 impl EwwTodo {
     pub fn from_todos(todos: Vec<&Todo>, config: &Config) -> Vec<Self> {
         todos
             .into_iter()
-            .map(|todo| Self {
-                state: todo.state.print(config),
-                description: todo
+            .map(|todo| {
+                let done = config.is_done(&todo.state);
+                let description = todo
                     .description
                     .0
                     .iter()
-                    .map(|op| op_to_string(op))
-                    .collect::<Vec<String>>(),
+                    .map(|op| op_to_string(op, config))
+                    .collect::<Vec<String>>();
+
+                Self {
+                    state: todo.state.print(config),
+                    state_class: if done { "done" } else { "open" }.to_owned(),
+                    // a done todo is struck through as a whole, so links and
+                    // formatting spans inside it follow the state too
+                    description: if done {
+                        vec![format!(
+                            "(box :class \"done\" :style \"text-decoration: line-through;\" :space-evenly false :halign \"start\" {})",
+                            description.join("")
+                        )]
+                    } else {
+                        description
+                    },
+                    contexts: todo.description.contexts(),
+                }
             })
             .collect()
     }
 }
 
-fn op_to_string(op: &TextOp) -> String {
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EwwSummary {
+    total: usize,
+    done: usize,
+    percent: usize,
+}
+
+impl EwwSummary {
+    pub fn new(total: usize, done: usize) -> Self {
+        Self {
+            total,
+            done,
+            percent: (done * 100).checked_div(total).unwrap_or(0),
+        }
+    }
+
+    pub fn from_todos(todos: &[&Todo], config: &Config) -> Self {
+        Self::new(
+            todos.len(),
+            todos
+                .iter()
+                .filter(|todo| config.is_done(&todo.state))
+                .count(),
+        )
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EwwSummarized {
+    summary: EwwSummary,
+    todos: Vec<EwwTodo>,
+}
+
+impl EwwSummarized {
+    pub fn from_todos(todos: Vec<&Todo>, config: &Config) -> Self {
+        Self {
+            summary: EwwSummary::from_todos(&todos, config),
+            todos: EwwTodo::from_todos(todos, config),
+        }
+    }
+}
+
+fn op_to_string(op: &TextOp, config: &Config) -> String {
     match op {
         TextOp::Verbatim(ops) => format!(
             "(box :style \"color: #c3e88d;\" :halign \"start\" {})",
-            ops.into_iter()
-                .map(|op| op_to_string(op))
+            ops.iter()
+                .map(|op| op_to_string(op, config))
                 .collect::<Vec<String>>()
                 .join("")
         ),
         TextOp::Underline(ops) => format!(
             "(box :style \"text-decoration: underline;\" :halign \"start\" {})",
-            ops.into_iter()
-                .map(|op| op_to_string(op))
+            ops.iter()
+                .map(|op| op_to_string(op, config))
                 .collect::<Vec<String>>()
                 .join("")
         ),
         TextOp::Crossed(ops) => format!(
             "(box :style \"text-decoration: line-through;\" :halign \"start\" {})",
-            ops.into_iter()
-                .map(|op| op_to_string(op))
+            ops.iter()
+                .map(|op| op_to_string(op, config))
                 .collect::<Vec<String>>()
                 .join("")
         ),
         TextOp::Bold(ops) => format!(
             "(box :style \"font-weight: bold;\" :halign \"start\" {})",
-            ops.into_iter()
-                .map(|op| op_to_string(op))
+            ops.iter()
+                .map(|op| op_to_string(op, config))
                 .collect::<Vec<String>>()
                 .join("")
         ),
         TextOp::Italic(ops) => format!(
             "(box :style \"font-style: italic;\" :halign \"start\" {})",
-            ops.into_iter()
-                .map(|op| op_to_string(op))
+            ops.iter()
+                .map(|op| op_to_string(op, config))
                 .collect::<Vec<String>>()
                 .join("")
         ),
         TextOp::TextExtra(char, ops) => format!(
             "(box :space-evenly false :halign \"start\" (label :halign \"start\" :text \"{char}\") {})",
-            ops.into_iter()
-                .map(|op| op_to_string(op))
+            ops.iter()
+                .map(|op| op_to_string(op, config))
                 .collect::<Vec<String>>()
                 .join("")
         ),
+        TextOp::Colored(color, ops) => {
+            let ops = ops
+                .iter()
+                .map(|op| op_to_string(op, config))
+                .collect::<Vec<String>>()
+                .join("");
+
+            match config.color(color) {
+                Some((r, g, b)) => format!(
+                    "(box :style \"color: #{r:02x}{g:02x}{b:02x};\" :halign \"start\" {ops})"
+                ),
+                None => ops,
+            }
+        }
         TextOp::Normal(str) => format!("(label :halign \"start\" :text \"{str}\")"),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_format::parser::{Text, TodoState};
+
+    fn to_string(op: TextOp) -> String {
+        let config =
+            Config::load(r##"return { directory = "/tmp", colors = { accent = "#0f8" } }"##)
+                .unwrap();
+        op_to_string(&op, &config)
+    }
+
+    fn text(str: &str) -> Vec<TextOp> {
+        vec![TextOp::Normal(str.to_owned())]
+    }
+
+    #[test]
+    fn normal() {
+        assert_eq!(
+            to_string(TextOp::Normal("plain".to_owned())),
+            r#"(label :halign "start" :text "plain")"#
+        );
+    }
+
+    #[test]
+    fn styled() {
+        let label = r#"(label :halign "start" :text "x")"#;
+        for (op, style) in [
+            (TextOp::Verbatim(text("x")), "color: #c3e88d;"),
+            (TextOp::Underline(text("x")), "text-decoration: underline;"),
+            (TextOp::Crossed(text("x")), "text-decoration: line-through;"),
+            (TextOp::Bold(text("x")), "font-weight: bold;"),
+            (TextOp::Italic(text("x")), "font-style: italic;"),
+        ] {
+            assert_eq!(
+                to_string(op),
+                format!(r#"(box :style "{style}" :halign "start" {label})"#)
+            );
+        }
+    }
+
+    #[test]
+    fn text_extra() {
+        assert_eq!(
+            to_string(TextOp::TextExtra('@', text("home"))),
+            r#"(box :space-evenly false :halign "start" (label :halign "start" :text "@") (label :halign "start" :text "home"))"#
+        );
+    }
+
+    #[test]
+    fn colored() {
+        assert_eq!(
+            to_string(TextOp::Colored("accent".to_owned(), text("x"))),
+            r#"(box :style "color: #00ff88;" :halign "start" (label :halign "start" :text "x"))"#
+        );
+        assert_eq!(
+            to_string(TextOp::Colored("red".to_owned(), text("x"))),
+            r#"(box :style "color: #ff0000;" :halign "start" (label :halign "start" :text "x"))"#
+        );
+        assert_eq!(
+            to_string(TextOp::Colored("nope".to_owned(), text("x"))),
+            r#"(label :halign "start" :text "x")"#
+        );
+    }
+
+    #[test]
+    fn nested() {
+        assert_eq!(
+            to_string(TextOp::Bold(vec![
+                TextOp::Normal("a ".to_owned()),
+                TextOp::Italic(text("b")),
+            ])),
+            r#"(box :style "font-weight: bold;" :halign "start" (label :halign "start" :text "a ")(box :style "font-style: italic;" :halign "start" (label :halign "start" :text "b")))"#
+        );
+    }
+
+    #[test]
+    fn contexts_follow_the_description() {
+        let config = Config::load(r#"return { directory = "/tmp" }"#).unwrap();
+        // a todo coming back from a lua transform has no parsed contexts
+        let todo = Todo {
+            state: TodoState::resolve(&config, " "),
+            description: Text(text("call @office now")),
+            contexts: vec![],
+            estimate: None,
+            anchor: None,
+            depends_on: vec![],
+            completed: None,
+            number: None,
+        };
+        let eww = EwwTodo::from_todos(vec![&todo], &config);
+
+        assert_eq!(eww[0].contexts, ["office"]);
+    }
+}
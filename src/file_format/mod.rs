@@ -1,3 +1,4 @@
 pub mod tokenizer;
 pub mod parser;
 pub mod eww;
+pub mod todo_txt;
@@ -3,15 +3,13 @@ use crate::file_format::tokenizer::Token;
 #[derive(Debug)]
 pub enum Error {
     NoTokens,
-    Other(String),
-    ExpectedV(Vec<&'static str>, Token),
+    ExpectedV(Vec<&'static str>, Box<Token>),
 }
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Error::NoTokens => write!(f, "Expected more tokens"),
-            Error::Other(str) => write!(f, "{str}"),
             Error::ExpectedV(expected, got) => write!(f, "Expected {expected:#?},\ngot {got:?}"),
         }
     }
@@ -31,6 +29,15 @@ impl ParserError {
     pub(crate) fn push(&mut self, err: ParserErrorStack) {
         self.stack.push(err);
     }
+
+    /// The error itself on a single line, without the parser stack.
+    pub fn message(&self) -> String {
+        self.err
+            .to_string()
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
 }
 
 impl std::fmt::Display for ParserError {
@@ -110,7 +117,7 @@ macro_rules! error {
             if matches!(res,  $( $pat )|+) {
                 Ok(res)
             } else {
-                Err(error!($func, Error::ExpectedV(vec!($( stringify!($pat) ),+), res.to_owned())))
+                Err(error!($func, Error::ExpectedV(vec!($( stringify!($pat) ),+), Box::new(res.to_owned()))))
             }
         } else {
             Err(error!($func, Error::NoTokens))
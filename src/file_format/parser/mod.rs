@@ -1,11 +1,16 @@
-use std::collections::VecDeque;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    ops::Range,
+    time::Duration,
+};
+
+use chrono::NaiveDate;
 
 use crate::config::Config;
 
-use super::tokenizer::{TextToken, Token};
+use super::tokenizer::{TextToken, Token, TokenizeError, Tokens, LINE_MARKERS};
 use error::{Error, ParserError, ParserErrorStack};
 use serde::{Deserialize, Serialize};
-use textwrap::termwidth;
 
 #[macro_use]
 pub mod error;
@@ -18,14 +23,175 @@ pub trait Parse {
     where
         Self: Sized;
     fn print(&self, config: &Config) -> String;
+    fn to_source(&self, config: &Config) -> String;
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct File(Vec<Heading>);
+#[derive(Debug, Serialize)]
+pub struct File {
+    /// The `key: value` metadata in the `---` fenced block at the top.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    front_matter: BTreeMap<String, String>,
+    headings: Vec<Heading>,
+}
+
+impl From<Vec<Heading>> for File {
+    fn from(headings: Vec<Heading>) -> Self {
+        Self {
+            front_matter: BTreeMap::new(),
+            headings,
+        }
+    }
+}
+
+// Files used to be written as just the array of their headings, which is
+// still accepted so older exports import.
+impl<'de> Deserialize<'de> for File {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Shape {
+            Headings(Vec<Heading>),
+            File {
+                #[serde(default)]
+                front_matter: BTreeMap<String, String>,
+                headings: Vec<Heading>,
+            },
+        }
+
+        Ok(match Shape::deserialize(deserializer)? {
+            Shape::Headings(headings) => headings.into(),
+            Shape::File {
+                front_matter,
+                headings,
+            } => Self {
+                front_matter,
+                headings,
+            },
+        })
+    }
+}
 
 impl File {
+    pub fn front_matter(&self) -> &BTreeMap<String, String> {
+        &self.front_matter
+    }
+
     pub fn headings(&self) -> &Vec<Heading> {
-        &self.0
+        &self.headings
+    }
+
+    pub fn headings_mut(&mut self) -> &mut Vec<Heading> {
+        &mut self.headings
+    }
+
+    pub fn into_headings(self) -> Vec<Heading> {
+        self.headings
+    }
+
+    /// Numbers the todos the way `toggle` indexes them: heading after
+    /// heading in file order, top to bottom within each, counting from 1.
+    pub fn number_todos(&mut self) {
+        for (number, todo) in self
+            .headings
+            .iter_mut()
+            .flat_map(|heading| heading.todos_mut())
+            .enumerate()
+        {
+            todo.number = Some(number + 1);
+        }
+    }
+
+    /// Folds headings sharing a name into the first one with that name,
+    /// appending their bodies in file order. Attributes of the first heading
+    /// win. Returns how many headings were merged away.
+    pub fn merge_headings(&mut self) -> usize {
+        let before = self.headings.len();
+        let mut merged: Vec<Heading> = Vec::with_capacity(before);
+
+        for mut heading in self.headings.drain(..) {
+            match merged.iter_mut().find(|other| other.name == heading.name) {
+                Some(other) => {
+                    for (key, value) in std::mem::take(&mut heading.attributes) {
+                        other.attributes.entry(key).or_insert(value);
+                    }
+                    other.append(heading);
+                }
+                None => merged.push(heading),
+            }
+        }
+
+        self.headings = merged;
+        before - self.headings.len()
+    }
+
+    /// Re-parses only the heading blocks touched by an edit instead of the
+    /// whole file. `source` is the edited text, `lines` the 1-based lines of
+    /// `source` the edit produced and `removed` how many lines it replaced.
+    ///
+    /// The heading before the edit is always re-parsed too, so lines typed
+    /// right above a heading still end up in the block they belong to.
+    pub fn reparse_range(
+        &mut self,
+        config: &Config,
+        source: &str,
+        lines: Range<usize>,
+        removed: usize,
+    ) -> Result<(), String> {
+        let delta = lines.len() as isize - removed as isize;
+        let old_end = (lines.start + removed).max(lines.start + 1);
+
+        let first = self
+            .headings
+            .iter()
+            .rposition(|heading| heading.line < lines.start)
+            .unwrap_or(0);
+        let last = self
+            .headings
+            .iter()
+            .rposition(|heading| heading.line < old_end)
+            .unwrap_or(0)
+            .max(first);
+        let start = if first == 0 {
+            1
+        } else {
+            self.headings[first].line
+        };
+        let end = self
+            .headings
+            .get(last + 1)
+            .map(|heading| heading.line.saturating_add_signed(delta));
+
+        let block = source
+            .split_inclusive('\n')
+            .enumerate()
+            .filter(|(i, _)| *i + 1 >= start && end.is_none_or(|end| *i + 1 < end))
+            .map(|(_, line)| line)
+            .collect::<String>();
+        let tokens: Tokens = block
+            .parse()
+            .map_err(|err: TokenizeError| err.to_string())?;
+        let parsed =
+            Self::parse(config, &mut tokens.to_vecdeque()).map_err(|err| err.to_string())?;
+        // the front matter is only in the block when it starts at the top
+        if start == 1 {
+            self.front_matter = parsed.front_matter;
+        }
+        let mut headings = parsed.headings;
+        for heading in &mut headings {
+            heading.line += start - 1;
+        }
+
+        let reparsed = headings.len();
+        if self.headings.is_empty() {
+            self.headings = headings;
+        } else {
+            self.headings.splice(first..=last, headings);
+        }
+        for heading in &mut self.headings[first + reparsed..] {
+            heading.line = heading.line.saturating_add_signed(delta);
+        }
+
+        Ok(())
     }
 }
 
@@ -34,41 +200,195 @@ impl Parse for File {
     where
         Self: Sized,
     {
+        log::trace!("parsing File at {:?}", tokens.front());
+        let mut front_matter = BTreeMap::new();
+        if let Some(Token::FrontMatter(_)) = tokens.front() {
+            let Some(Token::FrontMatter(parsed)) = tokens.pop_front() else {
+                unreachable!()
+            };
+            front_matter = parsed;
+            while tokens.front() == Some(&Token::Newline) {
+                tokens.pop_front();
+            }
+        }
         let mut headings = vec![];
 
         while !tokens.is_empty() {
             headings.push(error!(Heading::parse(config, tokens), "File")?);
         }
 
-        return Ok(Self(headings));
+        return Ok(Self {
+            front_matter,
+            headings,
+        });
     }
 
     fn check(tokens: &VecDeque<Token>) -> bool
     where
         Self: Sized,
     {
-        matches!(tokens[0], Token::Heading(_))
+        matches!(tokens[0], Token::Heading(..))
     }
 
     fn print(&self, config: &Config) -> String {
         format!(
             "{}",
-            self.0
+            self.headings
                 .iter()
                 .map(|heading| heading.print(&config))
                 .collect::<Vec<String>>()
-                .join("\n")
+                .join(if config.compact() { "" } else { "\n" })
         )
     }
+
+    fn to_source(&self, config: &Config) -> String {
+        let mut source = String::new();
+        if !self.front_matter.is_empty() {
+            source += "---\n";
+            for (key, value) in &self.front_matter {
+                source += &format!("{key}: {value}\n");
+            }
+            source += "---\n\n";
+        }
+        for (i, heading) in self.headings.iter().enumerate() {
+            source += &heading.to_source(config);
+
+            // headings are kept apart by one blank line, or by as many as
+            // they were when preserving them
+            let last = i + 1 == self.headings.len();
+            let blank_lines = match (config.preserve_blank_lines(), last) {
+                (true, true) => heading.blank_lines,
+                (true, false) => heading.blank_lines.max(1),
+                (false, true) => 0,
+                (false, false) => 1,
+            };
+            source += &"\n".repeat(blank_lines);
+        }
+
+        source
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Heading {
     name: String,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    attributes: BTreeMap<String, String>,
+    /// The text between the title and the first todo or bullet.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    preamble: Vec<PrintText>,
     body: Vec<UnderHeading>,
+    #[serde(skip)]
+    line: usize,
+    /// How many blank lines followed the block in its source file.
+    #[serde(skip)]
+    blank_lines: usize,
 }
 
 impl Heading {
+    pub fn new(name: String) -> Self {
+        Self {
+            name,
+            attributes: BTreeMap::new(),
+            preamble: vec![],
+            body: vec![],
+            line: 0,
+            blank_lines: 0,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The 1-based line the heading starts on in its source file.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn attribute(&self, key: &str) -> Option<&str> {
+        self.attributes.get(key).map(String::as_str)
+    }
+
+    /// The body in display order, with todos rearranged among their own slots
+    /// according to the heading's `sort` attribute (`state`, `alpha`,
+    /// `priority` or `none`). Todos don't carry a priority yet, so `priority`
+    /// keeps file order like `none`.
+    fn sorted_body(&self, config: &Config) -> Vec<&UnderHeading> {
+        let mut body = self.body.iter().collect::<Vec<_>>();
+        let slots = body
+            .iter()
+            .enumerate()
+            .filter(|(_, under)| matches!(under, UnderHeading::Todo(_)))
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        let mut todos = slots
+            .iter()
+            .filter_map(|&i| match body[i] {
+                UnderHeading::Todo(todo) => Some((body[i], todo)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        match self.attribute("sort") {
+            Some("state") => todos.sort_by_key(|(_, todo)| {
+                (
+                    config.state_rank(&todo.state),
+                    config.is_done(&todo.state),
+                    todo.state.to_source(config),
+                )
+            }),
+            Some("alpha") => {
+                todos.sort_by_key(|(_, todo)| todo.description.to_source(config).to_lowercase())
+            }
+            _ => return body,
+        }
+
+        for (slot, (under, _)) in slots.into_iter().zip(todos) {
+            body[slot] = under;
+        }
+
+        body
+    }
+
+    fn title(&self) -> String {
+        if self.attributes.is_empty() {
+            return self.name.to_owned();
+        }
+
+        let attributes = self
+            .attributes
+            .iter()
+            .map(|(key, value)| format!("{key}={value}"))
+            .collect::<Vec<String>>();
+
+        format!("{} {{{}}}", self.name, attributes.join(", "))
+    }
+
+    /// The title as `heading_style` has it shown.
+    fn styled_title(&self, config: &Config) -> String {
+        let title = self.title();
+        let Some(style) = &config.heading_style else {
+            return title;
+        };
+
+        let title = format!(
+            "{}{}",
+            style.prefix.as_deref().unwrap_or(""),
+            if style.uppercase {
+                self.name.to_uppercase() + &title[self.name.len()..]
+            } else {
+                title
+            }
+        );
+        match style.color.as_deref().and_then(|color| config.color(color)) {
+            Some((r, g, b)) if config.colored() => {
+                format!("\x1b[38;2;{r};{g};{b}m{title}\x1b[39m")
+            }
+            _ => title,
+        }
+    }
+
     pub fn todos(&self) -> Vec<&Todo> {
         self.body
             .iter()
@@ -78,6 +398,107 @@ impl Heading {
             })
             .collect()
     }
+
+    pub fn todos_mut(&mut self) -> Vec<&mut Todo> {
+        self.body
+            .iter_mut()
+            .filter_map(|under| match under {
+                UnderHeading::Todo(todo) => Some(todo),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.preamble.is_empty() && self.body.is_empty()
+    }
+
+    /// Whether the body is nothing but done todos.
+    pub fn is_done(&self, config: &Config) -> bool {
+        !self.body.is_empty()
+            && self.body.iter().all(|under| match under {
+                UnderHeading::Todo(todo) => config.is_done(&todo.state),
+                _ => false,
+            })
+    }
+
+    pub fn retain_todos<F: FnMut(&Todo) -> bool>(&mut self, mut f: F) {
+        self.body.retain(|under| match under {
+            UnderHeading::Todo(todo) => f(todo),
+            _ => true,
+        })
+    }
+
+    pub fn retain_only_todos(&mut self) {
+        self.preamble.clear();
+        self.body
+            .retain(|under| matches!(under, UnderHeading::Todo(_)))
+    }
+
+    /// Drops every todo after the first `max`, noting how many were left out
+    /// at the end of the body.
+    pub fn truncate_todos(&mut self, max: usize) {
+        let mut kept = 0;
+        self.retain_todos(|_| {
+            kept += 1;
+            kept <= max
+        });
+
+        if kept > max {
+            self.body
+                .push(UnderHeading::Text(PrintText(Text(vec![TextOp::Normal(
+                    format!("... and {} more", kept - max),
+                )]))));
+        }
+    }
+
+    /// Drops the done todos, summing them up in a line at the end of the
+    /// body instead.
+    pub fn collapse_done(&mut self, config: &Config) {
+        let mut done = 0;
+        self.retain_todos(|todo| {
+            let is_done = config.is_done(&todo.state);
+            done += usize::from(is_done);
+            !is_done
+        });
+
+        if done > 0 {
+            self.body
+                .push(UnderHeading::Text(PrintText(Text(vec![TextOp::Normal(
+                    format!("▸ {done} done"),
+                )]))));
+        }
+    }
+
+    pub fn push_todo(&mut self, todo: Todo) {
+        self.body.push(UnderHeading::Todo(todo))
+    }
+
+    /// Every piece of text under the heading: the preamble, todo
+    /// descriptions, bullets and plain text.
+    pub fn texts_mut(&mut self) -> Vec<&mut Text> {
+        self.preamble
+            .iter_mut()
+            .map(|PrintText(text)| text)
+            .chain(self.body.iter_mut().filter_map(|under| match under {
+                UnderHeading::Todo(todo) => Some(&mut todo.description),
+                UnderHeading::Bullet(bullet) => Some(&mut bullet.text),
+                UnderHeading::Text(PrintText(text)) => Some(text),
+                UnderHeading::Error { .. } => None,
+            }))
+            .collect()
+    }
+
+    /// Moves `other`'s preamble and body to the end of this one.
+    pub fn append(&mut self, other: Heading) {
+        if self.body.is_empty() {
+            self.preamble.extend(other.preamble);
+        } else {
+            self.body
+                .extend(other.preamble.into_iter().map(UnderHeading::Text));
+        }
+        self.body.extend(other.body)
+    }
 }
 
 impl Parse for Heading {
@@ -85,85 +506,268 @@ impl Parse for Heading {
     where
         Self: Sized,
     {
-        let name = error!("Heading", tokens, Heading);
+        log::trace!("parsing Heading at {:?}", tokens.front());
+        let (name, attributes, line) = if let Token::Heading(name, attributes, line) =
+            error!("Heading", tokens.pop_front(), [Token::Heading(..)])?
+        {
+            (name, attributes, line)
+        } else {
+            unreachable!()
+        };
         let _ = error!("Heading", tokens.pop_front(), [Token::Newline])?;
+        let mut preamble = vec![];
         let mut body = vec![];
+        let mut blank_lines = 0;
 
         loop {
             if tokens.is_empty() {
                 break;
             }
+            // a blank line ends the block, along with any more right after it
             if tokens[0] == Token::Newline {
-                tokens.pop_front();
+                while tokens.front() == Some(&Token::Newline) {
+                    tokens.pop_front();
+                    blank_lines += 1;
+                }
                 break;
             }
 
-            if Todo::check(&tokens) {
-                body.push(UnderHeading::Todo(error!(
-                    Todo::parse(config, tokens),
-                    "Heading"
-                )?))
+            // how many tokens are left after this line, to skip to when it
+            // fails to parse
+            let rest = tokens
+                .iter()
+                .position(|token| *token == Token::Newline)
+                .map_or(0, |end| tokens.len() - end - 1);
+            let line = if Todo::check(&tokens) {
+                Todo::parse(config, tokens).map(UnderHeading::Todo)
             } else if Bullet::check(&tokens) {
-                body.push(UnderHeading::Bullet(error!(
-                    Bullet::parse(config, tokens),
-                    "Heading"
-                )?));
-                let _ = error!("Heading", tokens.pop_front(), [Token::Newline])?;
+                Bullet::parse(config, tokens).and_then(|bullet| {
+                    error!("Heading", tokens.pop_front(), [Token::Newline])?;
+                    Ok(UnderHeading::Bullet(bullet))
+                })
             } else if Text::check(&tokens) {
-                body.push(UnderHeading::Text(error!(
-                    PrintText::parse(config, tokens),
-                    "Heading"
-                )?));
-                let _ = error!("Heading", tokens.pop_front(), [Token::Newline])?;
+                PrintText::parse(config, tokens).and_then(|text| {
+                    error!("Heading", tokens.pop_front(), [Token::Newline])?;
+                    Ok(UnderHeading::Text(text))
+                })
             } else if Heading::check(&tokens) {
-                return Err(error!(
-                    "Heading",
-                    Error::Other(format!("Can't have a heading in a heading"))
-                ));
+                // the next heading right away, with no blank line before it
+                break;
+            } else {
+                continue;
+            };
+
+            match line {
+                Ok(UnderHeading::Text(text)) if body.is_empty() => preamble.push(text),
+                Ok(line) => body.push(line),
+                // keeps the error in place of the line and carries on with
+                // the next one
+                Err(err) if config.lenient() => {
+                    body.push(UnderHeading::Error {
+                        error: err.message(),
+                    });
+                    tokens.drain(..tokens.len().saturating_sub(rest));
+                }
+                Err(err) => return error!(Err(err), "Heading"),
             }
         }
 
-        Ok(Self { name, body })
+        Ok(Self {
+            name,
+            attributes,
+            preamble,
+            body,
+            line,
+            blank_lines,
+        })
     }
 
     fn check(tokens: &VecDeque<Token>) -> bool
     where
         Self: Sized,
     {
-        matches!(tokens[0], Token::Heading(_))
+        matches!(tokens[0], Token::Heading(..))
     }
 
     fn print(&self, config: &Config) -> String {
-        let mut buf = format!("{}\n", self.name);
+        let mut buf = format!("{}\n", self.styled_title(config));
+        for text in &self.preamble {
+            buf += &text.print_indented(config, 0);
+        }
+        if !self.preamble.is_empty() && !self.body.is_empty() {
+            buf += "\n";
+        }
 
-        for body in &self.body {
-            if let UnderHeading::Text(text) = body {
-                buf = format!("{buf}{}", text.print(&config));
-                continue;
-            }
+        let indent = " ".repeat(config.body_indent());
+        for body in self.sorted_body(config) {
             buf = match body {
-                UnderHeading::Todo(todo) => format!("{buf}    {}\n", todo.print(&config)),
-                UnderHeading::Bullet(bullet) => format!("{buf}    {}\n", bullet.print(&config)),
-                UnderHeading::Text(text) => format!("{buf}{}\n", text.print(&config)),
+                UnderHeading::Todo(todo) => format!("{buf}{indent}{}\n", todo.print(config)),
+                UnderHeading::Bullet(bullet) => {
+                    format!("{buf}{indent}{}\n", bullet.print(config))
+                }
+                UnderHeading::Text(text) => format!("{buf}{}", text.print(config)),
+                UnderHeading::Error { error: err } if config.colored() => {
+                    format!("{buf}{indent}\x1b[2m{err}\x1b[22m\n")
+                }
+                UnderHeading::Error { error: err } => format!("{buf}{indent}{err}\n"),
             };
         }
 
         return buf;
     }
+
+    fn to_source(&self, config: &Config) -> String {
+        let mut buf = format!("# {}\n", self.title());
+        for text in &self.preamble {
+            buf = format!("{buf}{}\n", text.to_source(config));
+        }
+
+        for body in &self.body {
+            let line = match body {
+                UnderHeading::Todo(todo) => todo.to_source(config),
+                UnderHeading::Bullet(bullet) => bullet.to_source(config),
+                UnderHeading::Text(text) => text.to_source(config),
+                // only there when parsing leniently for display, never
+                // written back
+                UnderHeading::Error { .. } => continue,
+            };
+            buf = format!("{buf}{line}\n");
+        }
+
+        buf
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize)]
 #[serde(untagged)]
 enum UnderHeading {
     Todo(Todo),
     Bullet(Bullet),
     Text(PrintText),
+    /// A line that failed to parse in lenient mode, an `error` object in JSON.
+    Error {
+        error: String,
+    },
+}
+
+// The variants are told apart by shape rather than by trying each in turn,
+// so a value can't be read back as a different variant than it was written
+// as: text is an array, bullets have a `bullet` key, todos a `description`
+// and errors an `error`.
+impl<'de> Deserialize<'de> for UnderHeading {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+
+        let value = serde_json::Value::deserialize(deserializer)?;
+        match &value {
+            serde_json::Value::Array(_) => serde_json::from_value(value).map(Self::Text),
+            serde_json::Value::Object(object) if object.contains_key("bullet") => {
+                serde_json::from_value(value).map(Self::Bullet)
+            }
+            serde_json::Value::Object(object) if object.contains_key("description") => {
+                serde_json::from_value(value).map(Self::Todo)
+            }
+            serde_json::Value::Object(object) if object.contains_key("error") => {
+                serde_json::from_value(object["error"].clone()).map(|error| Self::Error { error })
+            }
+            _ => {
+                return Err(D::Error::custom(
+                    "expected a todo, a bullet, an error or an array of text",
+                ))
+            }
+        }
+        .map_err(D::Error::custom)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Todo {
     pub state: TodoState,
     pub description: Text,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub contexts: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub estimate: Option<Duration>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub anchor: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub depends_on: Vec<String>,
+    /// The day the todo was marked done on, stamped as `^YYYY-MM-DD`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub completed: Option<NaiveDate>,
+    /// The index `toggle` takes for this todo, printed in front of it when
+    /// set. See [`File::number_todos`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub number: Option<usize>,
+}
+
+impl Todo {
+    /// Sets the state from its source form, resolving it through
+    /// `todo_state` the same way parsing does. Going from open to done
+    /// stamps today's date, going back takes the stamp off.
+    pub fn set_state(&mut self, config: &Config, state: &str) {
+        let was_done = config.is_done(&self.state);
+        self.state = TodoState::resolve(config, state.trim());
+
+        match (was_done, config.is_done(&self.state)) {
+            (false, true) => self.set_completed(Some(chrono::Local::now().date_naive())),
+            (true, false) => self.set_completed(None),
+            _ => {}
+        }
+    }
+
+    /// Replaces the `^YYYY-MM-DD` completion stamp, appending the new one to
+    /// the end of the description, or only removing the old one with `None`.
+    pub fn set_completed(&mut self, completed: Option<NaiveDate>) {
+        self.description.replace_normal(false, |str| {
+            let mut stripped = str
+                .split_inclusive(char::is_whitespace)
+                .filter(|chunk| completion_date(chunk.trim_end()).is_none())
+                .collect::<String>();
+            if stripped.len() != str.len() && !str.ends_with(char::is_whitespace) {
+                stripped.truncate(stripped.trim_end().len());
+            }
+
+            stripped
+        });
+
+        if let Some(date) = completed {
+            let stamp = format!("^{}", date.format("%Y-%m-%d"));
+            match self.description.0.last_mut() {
+                Some(TextOp::Normal(str)) => {
+                    str.push(' ');
+                    str.push_str(&stamp);
+                }
+                Some(_) => self.description.0.push(TextOp::Normal(format!(" {stamp}"))),
+                None => self.description.0.push(TextOp::Normal(stamp)),
+            }
+        }
+        self.completed = completed;
+    }
+
+    /// Advances the state to the next one in `state_cycle`, or flips between
+    /// open and the first done state when no cycle is configured.
+    pub fn toggle(&mut self, config: &Config) -> &TodoState {
+        let next = if config.state_cycle.is_empty() {
+            if config.is_done(&self.state) {
+                ""
+            } else {
+                config.done_marker()
+            }
+        } else {
+            let current = self.state.to_source(config);
+            config
+                .state_cycle
+                .iter()
+                .position(|state| state.trim() == current.trim())
+                .map_or(&config.state_cycle[0], |position| {
+                    &config.state_cycle[(position + 1) % config.state_cycle.len()]
+                })
+        };
+
+        self.set_state(config, next);
+        &self.state
+    }
 }
 
 impl Parse for Todo {
@@ -171,13 +775,23 @@ impl Parse for Todo {
     where
         Self: Sized,
     {
+        log::trace!("parsing Todo at {:?}", tokens.front());
         let _ = error!("Todo", tokens.pop_front(), [Token::BracketOpen])?;
         let state = error!(TodoState::parse(config, tokens), "Todo")?;
         let _ = error!("Todo", tokens.pop_front(), [Token::BracketClose])?;
         let description = error!(Text::parse(config, tokens), "Todo")?;
         let _ = error!("Todo", tokens.pop_front(), [Token::Newline])?;
 
-        Ok(Self { state, description })
+        Ok(Self {
+            state,
+            contexts: description.contexts(),
+            estimate: description.estimate().ok().flatten(),
+            anchor: description.anchor(),
+            depends_on: description.depends_on(),
+            completed: description.completed(),
+            number: None,
+            description,
+        })
     }
 
     fn check(tokens: &VecDeque<Token>) -> bool
@@ -188,29 +802,31 @@ impl Parse for Todo {
     }
 
     fn print(&self, config: &Config) -> String {
-        let brackets = if let Some(ops) = &config.todo_state_ops {
-            ops.brackets
-        } else {
-            true
-        };
-        let state = if self.state.empty() {
-            if let Some(ops) = &config.todo_state_ops {
-                ops.default.to_owned()
-            } else {
-                " ".to_owned()
-            }
-        } else {
-            self.state.print(config)
+        let number = match self.number {
+            Some(number) if config.colored() => format!("\x1b[2m{number}.\x1b[22m "),
+            Some(number) => format!("{number}. "),
+            None => String::new(),
         };
 
-        if brackets {
-            format!("[{state}] {}", self.description.print(config))
-        } else {
-            format!("{state} {}", self.description.print(config))
-        }
+        format!(
+            "{number}{} {}",
+            self.state.print(config),
+            self.description.print(config)
+        )
+    }
+
+    fn to_source(&self, config: &Config) -> String {
+        format!(
+            "[{}] {}",
+            escape_state(&self.state.to_source(config)),
+            self.description.to_source(config)
+        )
     }
 }
 
+/// A state as written in the source, `Defined` when it's a `todo_state` key.
+/// Defined states are only mapped to their display form when printed, so
+/// states sharing a display are still told apart when written back.
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum TodoState {
@@ -219,9 +835,19 @@ pub enum TodoState {
 }
 
 impl TodoState {
-    fn empty(&self) -> bool {
+    pub fn as_str(&self) -> &str {
         match self {
-            Self::Defined(str) | Self::Other(str) => str.is_empty(),
+            Self::Defined(str) | Self::Other(str) => str,
+        }
+    }
+
+    pub fn resolve(config: &Config, str: &str) -> Self {
+        let str = config.state_aliases.get(str).map_or(str, String::as_str);
+
+        if config.todo_state.contains_key(str) {
+            Self::Defined(str.to_owned())
+        } else {
+            Self::Other(str.to_owned())
         }
     }
 }
@@ -231,6 +857,7 @@ impl Parse for TodoState {
     where
         Self: Sized,
     {
+        log::trace!("parsing TodoState at {:?}", tokens.front());
         let str = if let Token::Inside(text) =
             error!("TodoState", tokens.pop_front(), [Token::Inside(_)])?
         {
@@ -239,11 +866,7 @@ impl Parse for TodoState {
             unreachable!()
         };
 
-        Ok(if let Some(state) = config.todo_state.get(&str) {
-            Self::Defined(state.to_owned())
-        } else {
-            Self::Other(str.to_owned())
-        })
+        Ok(Self::resolve(config, &str))
     }
 
     fn check(tokens: &VecDeque<Token>) -> bool
@@ -258,7 +881,7 @@ impl Parse for TodoState {
 
     fn print(&self, config: &Config) -> String {
         let str = match self {
-            Self::Defined(str) => str,
+            Self::Defined(key) => config.todo_state.get(key).unwrap_or(key),
             Self::Other(str) => str,
         };
         let brackets = if let Some(ops) = &config.todo_state_ops {
@@ -275,6 +898,16 @@ impl Parse for TodoState {
         } else {
             str
         };
+        // padded so states of different widths keep descriptions aligned
+        let state = format!(
+            "{state}{}",
+            " ".repeat(
+                config
+                    .state_width
+                    .unwrap_or(0)
+                    .saturating_sub(textwrap::core::display_width(state))
+            )
+        );
 
         if brackets {
             format!("[{state}]")
@@ -282,11 +915,40 @@ impl Parse for TodoState {
             format!("{state}")
         }
     }
+
+    fn to_source(&self, _: &Config) -> String {
+        let str = self.as_str();
+
+        if str.is_empty() {
+            " ".to_owned()
+        } else {
+            str.to_owned()
+        }
+    }
+}
+
+/// Escapes a state for the inside of a todo's brackets. A backslash is only
+/// doubled where it would otherwise read as an escape, so a state like `a\b`
+/// is written back unchanged.
+fn escape_state(state: &str) -> String {
+    let mut escaped = String::with_capacity(state.len());
+    let mut chars = state.chars().peekable();
+    while let Some(char) = chars.next() {
+        match char {
+            ']' => escaped.push_str("\\]"),
+            '\\' if matches!(chars.peek(), None | Some(']' | '\\')) => escaped.push_str("\\\\"),
+            _ => escaped.push(char),
+        }
+    }
+
+    escaped
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct Bullet {
     bullet: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    state: Option<String>,
     text: Text,
 }
 
@@ -295,9 +957,33 @@ impl Parse for Bullet {
     where
         Self: Sized,
     {
+        log::trace!("parsing Bullet at {:?}", tokens.front());
+        let mut text = error!(Text::parse(config, tokens), "Bullet")?;
+
+        // `- (state) text`, the state being a single word
+        let mut state = None;
+        if let Some(TextOp::Normal(str)) = text.0.first_mut() {
+            if let Some((inner, rest)) = str
+                .strip_prefix('(')
+                .and_then(|str| str.split_once(')'))
+                .filter(|(inner, rest)| {
+                    !inner.is_empty()
+                        && !inner.contains(char::is_whitespace)
+                        && (rest.is_empty() || rest.starts_with(' '))
+                })
+            {
+                state = Some(inner.to_owned());
+                *str = rest.trim_start().to_owned();
+                if str.is_empty() {
+                    text.0.remove(0);
+                }
+            }
+        }
+
         Ok(Self {
             bullet: true,
-            text: error!(Text::parse(config, tokens), "Bullet")?,
+            state,
+            text,
         })
     }
 
@@ -309,10 +995,28 @@ impl Parse for Bullet {
     }
 
     fn print(&self, config: &Config) -> String {
-        if let Some(bullet) = &config.bullet_point {
-            format!("{bullet} {}", self.text.print(config))
-        } else {
-            format!("- {}", self.text.print(config))
+        let bullet = config.bullet_point.as_deref().unwrap_or("-");
+
+        match &self.state {
+            Some(state) if !config.colored() => {
+                format!("{bullet} ({state}) {}", self.text.print(config))
+                    .trim_end()
+                    .to_owned()
+            }
+            Some(state) if self.text.0.is_empty() => format!("{bullet} \x1b[1m({state})\x1b[22m"),
+            Some(state) => format!(
+                "{bullet} \x1b[1m({state})\x1b[22m {}",
+                self.text.print(config)
+            ),
+            None => format!("{bullet} {}", self.text.print(config)),
+        }
+    }
+
+    fn to_source(&self, config: &Config) -> String {
+        match &self.state {
+            Some(state) if self.text.0.is_empty() => format!("- ({state})"),
+            Some(state) => format!("- ({state}) {}", self.text.to_source(config)),
+            None => format!("- {}", self.text.to_source(config)),
         }
     }
 }
@@ -325,6 +1029,7 @@ impl Parse for PrintText {
     where
         Self: Sized,
     {
+        log::trace!("parsing PrintText at {:?}", tokens.front());
         Ok(Self(Text::parse(config, tokens)?))
     }
     fn check(tokens: &VecDeque<Token>) -> bool
@@ -335,9 +1040,63 @@ impl Parse for PrintText {
     }
 
     fn print(&self, config: &Config) -> String {
+        self.print_indented(config, config.body_indent())
+    }
+
+    fn to_source(&self, config: &Config) -> String {
+        let source = self.0.to_source(config);
+
+        if LINE_MARKERS.iter().any(|marker| source.starts_with(marker)) {
+            format!("\\{source}")
+        } else {
+            source
+        }
+    }
+}
+
+impl PrintText {
+    /// Like `print`, indented by `indent` instead of `body_indent`.
+    fn print_indented(&self, config: &Config, indent: usize) -> String {
+        // stands in for spaces inside verbatim spans so the wrapping doesn't
+        // split them
+        const CODE_SPACE: char = '\u{E000}';
+
+        let Text(ops) = &self.0;
+        let text = ops
+            .iter()
+            .map(|op| match op {
+                TextOp::Verbatim(_) if !config.wrap_code() => {
+                    op.print(config).replace(' ', &CODE_SPACE.to_string())
+                }
+                _ => op.print(config),
+            })
+            .collect::<String>()
+            .replace('\t', &" ".repeat(config.tab_width()));
+
         textwrap::indent(
-            &textwrap::fill(&self.0.print(config), termwidth() - 4),
-            "    ",
+            &match config.wrap_width() {
+                Some(width) if config.compact() => {
+                    let lines = textwrap::wrap(&text, width.saturating_sub(indent + 1).max(1));
+                    if lines.len() > 1 {
+                        format!("{}…", lines[0])
+                    } else {
+                        text
+                    }
+                    .replace(CODE_SPACE, " ")
+                }
+                Some(width) if config.wrap_code() => {
+                    textwrap::fill(&text, width.saturating_sub(indent).max(1))
+                }
+                Some(width) => textwrap::fill(
+                    &text,
+                    textwrap::Options::new(width.saturating_sub(indent).max(1))
+                        .word_separator(textwrap::WordSeparator::AsciiSpace)
+                        .break_words(false),
+                )
+                .replace(CODE_SPACE, " "),
+                None => text.replace(CODE_SPACE, " "),
+            },
+            &" ".repeat(indent),
         ) + "\n"
     }
 }
@@ -345,11 +1104,158 @@ impl Parse for PrintText {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Text(pub Vec<TextOp>);
 
+/// The date of a `^YYYY-MM-DD` completion stamp.
+fn completion_date(word: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(word.strip_prefix('^')?, "%Y-%m-%d").ok()
+}
+
+impl Text {
+    /// The words of the text outside of verbatim spans.
+    fn plain_words(&self) -> Vec<&str> {
+        fn walk<'a>(ops: &'a [TextOp], words: &mut Vec<&'a str>) {
+            for op in ops {
+                match op {
+                    TextOp::Verbatim(_) => {}
+                    TextOp::Normal(str) => words.extend(str.split_whitespace()),
+                    TextOp::Underline(ops)
+                    | TextOp::Crossed(ops)
+                    | TextOp::Bold(ops)
+                    | TextOp::Italic(ops)
+                    | TextOp::TextExtra(_, ops)
+                    | TextOp::Colored(_, ops) => walk(ops, words),
+                }
+            }
+        }
+
+        let mut words = vec![];
+        walk(&self.0, &mut words);
+        words
+    }
+
+    /// The `@context` words in the text, without the `@`, skipping verbatim
+    /// spans and `@DATE`s (anything starting with a digit).
+    pub fn contexts(&self) -> Vec<String> {
+        let mut contexts: Vec<String> = vec![];
+        for word in self.plain_words() {
+            let Some(context) = word.strip_prefix('@') else {
+                continue;
+            };
+            let context = context.trim_end_matches(|c: char| c.is_ascii_punctuation());
+
+            if !context.is_empty()
+                && !context.starts_with(|c: char| c.is_ascii_digit())
+                && !contexts.iter().any(|other| other == context)
+            {
+                contexts.push(context.to_owned());
+            }
+        }
+
+        contexts
+    }
+
+    /// The id other todos can refer to this one by, given as `^ID`. A date
+    /// after the `^` is a completion stamp instead.
+    pub fn anchor(&self) -> Option<String> {
+        self.plain_words()
+            .into_iter()
+            .filter(|word| completion_date(word).is_none())
+            .find_map(|word| word.strip_prefix('^').filter(|id| !id.is_empty()))
+            .map(str::to_owned)
+    }
+
+    /// The day stamped by a `^YYYY-MM-DD` completion trailer.
+    pub fn completed(&self) -> Option<NaiveDate> {
+        self.plain_words().into_iter().find_map(completion_date)
+    }
+
+    /// The anchors of the todos this one has to wait for, given as
+    /// `>after:ID`.
+    pub fn depends_on(&self) -> Vec<String> {
+        self.plain_words()
+            .into_iter()
+            .filter_map(|word| word.strip_prefix(">after:").filter(|id| !id.is_empty()))
+            .map(str::to_owned)
+            .collect()
+    }
+
+    /// The `~DURATION` estimate the text ends in, not counting a completion
+    /// stamp, such as `~30m`, `~2h` or
+    /// `~1h30m`, with `m`, `h` and `d` units. An error when the last word
+    /// starts with `~` but isn't a duration.
+    pub fn estimate(&self) -> Result<Option<Duration>, String> {
+        let Some(TextOp::Normal(str)) = self.0.last() else {
+            return Ok(None);
+        };
+        let Some(estimate) = str
+            .split_whitespace()
+            .rev()
+            .find(|word| completion_date(word).is_none())
+            .and_then(|word| word.strip_prefix('~'))
+        else {
+            return Ok(None);
+        };
+
+        let invalid = || format!("Invalid estimate \"~{estimate}\"");
+        if estimate.is_empty() {
+            return Err(invalid());
+        }
+
+        let mut minutes: u64 = 0;
+        let mut rest = estimate;
+        while !rest.is_empty() {
+            let digits = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .ok_or_else(invalid)?;
+            let amount = rest[..digits].parse::<u64>().map_err(|_| invalid())?;
+            let unit = rest[digits..].chars().next().unwrap();
+            let per_unit = match unit {
+                'm' => 1,
+                'h' => 60,
+                'd' => 24 * 60,
+                _ => return Err(invalid()),
+            };
+            // too long to be an estimate of anything, and would overflow
+            minutes = amount
+                .checked_mul(per_unit)
+                .and_then(|amount| minutes.checked_add(amount))
+                .ok_or_else(invalid)?;
+            rest = &rest[digits + unit.len_utf8()..];
+        }
+
+        Ok(Some(Duration::from_secs(
+            minutes.checked_mul(60).ok_or_else(invalid)?,
+        )))
+    }
+
+    /// Rewrites the plain text with `f`, including inside formatting spans.
+    /// Verbatim spans are left alone unless `verbatim` is set.
+    pub fn replace_normal<F: FnMut(&str) -> String>(&mut self, verbatim: bool, mut f: F) {
+        fn walk<F: FnMut(&str) -> String>(ops: &mut [TextOp], verbatim: bool, f: &mut F) {
+            for op in ops {
+                match op {
+                    TextOp::Verbatim(_) if !verbatim => {}
+                    TextOp::Normal(str) => *str = f(str),
+                    TextOp::Verbatim(ops)
+                    | TextOp::Underline(ops)
+                    | TextOp::Crossed(ops)
+                    | TextOp::Bold(ops)
+                    | TextOp::Italic(ops)
+                    | TextOp::TextExtra(_, ops)
+                    | TextOp::Colored(_, ops) => walk(ops, verbatim, f),
+                }
+            }
+        }
+
+        walk(&mut self.0, verbatim, &mut f)
+    }
+}
+
 impl Parse for Text {
     fn parse(_: &Config, tokens: &mut VecDeque<Token>) -> Result<Self, ParserError>
     where
         Self: Sized,
     {
+        log::trace!("parsing Text at {:?}", tokens.front());
         Ok(Self(
             match error!(
                 "Text",
@@ -372,7 +1278,16 @@ impl Parse for Text {
         matches!(tokens[0], Token::Text(_) | Token::Bullet(_))
     }
 
-    fn print(&self, _: &Config) -> String {
+    fn print(&self, config: &Config) -> String {
+        self.0
+            .iter()
+            .map(|op| op.print(config))
+            .collect::<Vec<String>>()
+            .join("")
+            .replace('\t', &" ".repeat(config.tab_width()))
+    }
+
+    fn to_source(&self, _: &Config) -> String {
         self.0
             .iter()
             .map(|op| op.to_string())
@@ -389,9 +1304,75 @@ pub enum TextOp {
     Bold(Vec<TextOp>),
     Italic(Vec<TextOp>),
     TextExtra(char, Vec<TextOp>),
+    Colored(String, Vec<TextOp>),
     Normal(String),
 }
 
+impl TextOp {
+    /// Renders the op for the terminal, same as its `Display` source form
+    /// except that colored spans become ANSI escapes (or plain text when the
+    /// color isn't known).
+    pub fn print(&self, config: &Config) -> String {
+        let print = |ops: &Vec<TextOp>| {
+            ops.iter()
+                .map(|op| op.print(config))
+                .collect::<Vec<String>>()
+                .join("")
+        };
+
+        match self {
+            Self::Verbatim(ops) => format!("`{}`", print(ops)),
+            Self::Underline(ops) => format!("_{}_", print(ops)),
+            Self::Crossed(ops) => format!("-{}-", print(ops)),
+            Self::Bold(ops) => format!("*{}*", print(ops)),
+            Self::Italic(ops) => format!("/{}/", print(ops)),
+            Self::TextExtra(char, ops) => format!("{char}{}", print(ops)),
+            Self::Colored(color, ops) => match config.color(color) {
+                Some((r, g, b)) if config.colored() => {
+                    format!("\x1b[38;2;{r};{g};{b}m{}\x1b[39m", print(ops))
+                }
+                _ => print(ops),
+            },
+            Self::Normal(str) if config.colored() && !config.highlight.is_empty() => {
+                highlight(config, str)
+            }
+            Self::Normal(str) => str.to_owned(),
+        }
+    }
+}
+
+/// Shows the parts of `str` matching any of the `highlight` patterns in
+/// reverse video, merging matches that overlap or touch into one span.
+fn highlight(config: &Config, str: &str) -> String {
+    let mut matches = config
+        .highlight
+        .iter()
+        .flat_map(|pattern| pattern.find_iter(str).map(|found| found.range()))
+        .collect::<Vec<_>>();
+    matches.sort_by_key(|range| range.start);
+
+    let mut spans: Vec<Range<usize>> = vec![];
+    for range in matches {
+        match spans.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => spans.push(range),
+        }
+    }
+
+    let mut buf = String::new();
+    let mut end = 0;
+    for span in spans {
+        buf = format!(
+            "{buf}{}\x1b[7m{}\x1b[27m",
+            &str[end..span.start],
+            &str[span.clone()]
+        );
+        end = span.end;
+    }
+
+    buf + &str[end..]
+}
+
 impl From<TextToken> for TextOp {
     fn from(value: TextToken) -> Self {
         match value {
@@ -414,6 +1395,9 @@ impl From<TextToken> for TextOp {
                 char,
                 tokens.into_iter().map(|token| Self::from(token)).collect(),
             ),
+            TextToken::Colored(color, tokens) => {
+                Self::Colored(color, tokens.into_iter().map(Self::from).collect())
+            }
             TextToken::Text(str) => Self::Normal(str),
         }
     }
@@ -469,8 +1453,364 @@ impl std::fmt::Display for TextOp {
                             .join("")
                     )
                 }
+                Self::Colored(color, strs) => format!(
+                    "{{{color}:{}}}",
+                    strs.iter()
+                        .map(|str| str.to_string())
+                        .collect::<Vec<String>>()
+                        .join("")
+                ),
                 Self::Normal(str) => str.to_owned(),
             }
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(extra: &str) -> Config {
+        let mut config =
+            Config::load(&format!("return {{ directory = \"/tmp\", {extra} }}")).unwrap();
+        config.colored = Some(false);
+        config
+    }
+
+    fn parse(config: &Config, source: &str) -> File {
+        let tokens: Tokens = source.parse().unwrap();
+        File::parse(config, &mut tokens.to_vecdeque()).unwrap()
+    }
+
+    #[test]
+    fn escaped_bracket_is_text_not_a_todo() {
+        let config = config("");
+        let source = "# A\n[ ] task\n\\[literal]\n";
+        let file = parse(&config, source);
+
+        let body = &file.headings()[0].body;
+        assert!(matches!(body[0], UnderHeading::Todo(_)));
+        assert!(
+            matches!(&body[1], UnderHeading::Text(text) if text.0.to_source(&config) == "[literal]")
+        );
+        assert_eq!(file.to_source(&config), source);
+    }
+
+    /// The column each body line's content starts at.
+    fn columns(printed: &str) -> Vec<usize> {
+        printed
+            .lines()
+            .skip(1)
+            .map(|line| line.len() - line.trim_start().len())
+            .collect()
+    }
+
+    #[test]
+    fn text_lines_up_with_todos() {
+        let source = "# A\n[ ] todo\nsome text\n- bullet\n";
+
+        let default = config("");
+        assert_eq!(columns(&parse(&default, source).print(&default)), [4, 4, 4]);
+
+        let two = config("body_indent = 2");
+        let printed = parse(&two, source).print(&two);
+        assert_eq!(printed, "A\n  [ ] todo\n  some text\n  - bullet\n");
+
+        let flush = config("body_indent = 0");
+        assert_eq!(columns(&parse(&flush, source).print(&flush)), [0, 0, 0]);
+    }
+
+    #[test]
+    fn text_wraps_at_wrap_width() {
+        let words = "lorem ipsum dolor sit amet ".repeat(6);
+        let source = format!("# A\n[ ] todo\n{}\n", words.trim_end());
+        let text_lines = |extra: &str| {
+            let config = config(extra);
+            parse(&config, &source)
+                .print(&config)
+                .lines()
+                .skip(2)
+                .map(str::to_owned)
+                .collect::<Vec<_>>()
+        };
+
+        for width in [40, 80] {
+            let lines = text_lines(&format!("wrap_width = {width}"));
+            assert!(lines.len() > 1);
+            assert!(lines.iter().all(|line| line.len() <= width));
+            assert!(lines.iter().all(|line| line.starts_with("    ")));
+            assert_eq!(
+                lines
+                    .iter()
+                    .map(|line| line.trim())
+                    .collect::<Vec<_>>()
+                    .join(" "),
+                words.trim_end()
+            );
+        }
+
+        assert_eq!(
+            text_lines("wrap_width = 0"),
+            [format!("    {}", words.trim_end())]
+        );
+    }
+
+    #[test]
+    fn state_width_pads_states_to_one_column() {
+        let source = "# A\n[ ] open\n[x] short\n[done] long\n";
+        let states = "todo_state = { x = \"x\", done = \"done\" }";
+
+        let padded = config(&format!("{states}, state_width = 4"));
+        assert_eq!(
+            parse(&padded, source).print(&padded),
+            "A\n    [    ] open\n    [x   ] short\n    [done] long\n"
+        );
+
+        let bare = config(&format!(
+            "{states}, state_width = 4, todo_state_ops = {{ default = \"-\", brackets = false }}"
+        ));
+        assert_eq!(
+            parse(&bare, source).print(&bare),
+            "A\n    -    open\n    x    short\n    done long\n"
+        );
+
+        let unpadded = config(states);
+        assert_eq!(
+            parse(&unpadded, source).print(&unpadded),
+            "A\n    [ ] open\n    [x] short\n    [done] long\n"
+        );
+        assert_eq!(parse(&padded, source).to_source(&padded), source);
+    }
+
+    #[test]
+    fn tabs_in_text_expand_to_tab_width() {
+        let source = "# A\n\t[ ] todo\n\tsome\ttext\n";
+        let file = parse(&config(""), source);
+        assert!(matches!(file.headings()[0].body[0], UnderHeading::Todo(_)));
+
+        let default = config("");
+        assert_eq!(file.print(&default), "A\n    [ ] todo\n    some    text\n");
+
+        let two = config("tab_width = 2");
+        assert_eq!(file.print(&two), "A\n    [ ] todo\n    some  text\n");
+    }
+
+    #[test]
+    fn headings_without_a_body() {
+        let config = config("");
+
+        let file = parse(&config, "# Title");
+        assert_eq!(file.headings().len(), 1);
+        assert_eq!(file.headings()[0].name(), "Title");
+        assert!(file.headings()[0].body.is_empty());
+
+        let source = "# A\n# B\n# C\n[ ] todo\n";
+        let file = parse(&config, source);
+        assert_eq!(
+            file.headings()
+                .iter()
+                .map(|heading| (heading.name(), heading.body.len()))
+                .collect::<Vec<_>>(),
+            [("A", 0), ("B", 0), ("C", 1)]
+        );
+        assert_eq!(parse(&config, &file.to_source(&config)).headings().len(), 3);
+    }
+
+    #[test]
+    fn escaped_states_round_trip() {
+        let config = config("");
+        let state = |source: &str| match &parse(&config, source).headings()[0].body[0] {
+            UnderHeading::Todo(todo) => todo.state.as_str().to_owned(),
+            other => panic!("expected a todo, got {other:?}"),
+        };
+
+        for (source, expected) in [
+            ("# A\n[a\\]b] todo\n", "a]b"),
+            ("# A\n[a\\b] todo\n", "a\\b"),
+            ("# A\n[end\\\\] todo\n", "end\\"),
+            ("# A\n[\\\\\\]] todo\n", "\\]"),
+        ] {
+            assert_eq!(state(source), expected);
+            assert_eq!(parse(&config, source).to_source(&config), source);
+        }
+
+        assert_eq!(state("# A\n[a\\\\b] todo\n"), "a\\b");
+    }
+
+    #[test]
+    fn blank_line_runs_between_headings() {
+        let source = "# A\n[ ] a\n\n\n\n# B\n[ ] b\n\n# C\n[ ] c\n\n\n";
+
+        let collapse = config("");
+        let file = parse(&collapse, source);
+        assert_eq!(file.headings().len(), 3);
+        assert_eq!(
+            file.to_source(&collapse),
+            "# A\n[ ] a\n\n# B\n[ ] b\n\n# C\n[ ] c\n"
+        );
+
+        let preserve = config("preserve_blank_lines = true");
+        assert_eq!(parse(&preserve, source).to_source(&preserve), source);
+    }
+
+    #[test]
+    fn lenient_errors_serialize_as_objects() {
+        let mut config = config("");
+        config.lenient = Some(true);
+        let file = parse(&config, "# A\n[ ] ok\n[x] \n[ ] after\n");
+
+        let json = serde_json::to_value(&file.headings()[0]).unwrap();
+        let body = &json["body"];
+        assert!(body[1]["error"].is_string());
+
+        let back: Heading = serde_json::from_value(json.clone()).unwrap();
+        assert!(matches!(
+            &back.body[1],
+            UnderHeading::Error { error } if error == body[1]["error"].as_str().unwrap()
+        ));
+    }
+
+    #[test]
+    fn file_is_always_a_json_object() {
+        let config = config("");
+
+        let plain = serde_json::to_value(parse(&config, "# A\n[ ] a\n")).unwrap();
+        assert!(plain.get("front_matter").is_none());
+        assert_eq!(plain["headings"][0]["name"], "A");
+
+        let with_front_matter =
+            serde_json::to_value(parse(&config, "---\nk: v\n---\n\n# A\n[ ] a\n")).unwrap();
+        assert_eq!(with_front_matter["front_matter"]["k"], "v");
+        assert_eq!(with_front_matter["headings"], plain["headings"]);
+
+        // the array older versions wrote
+        let legacy: File = serde_json::from_value(plain["headings"].clone()).unwrap();
+        assert_eq!(legacy.to_source(&config), "# A\n[ ] a\n");
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let config = config("todo_state = { x = \"x\" }");
+        let source = "# Work\n[ ] write *report* @office\n[x] call bob ^2024-01-02\n- a bullet\nsome text\n\n# Home\n[ ] clean `kitchen`\n";
+        let file = parse(&config, source);
+
+        let json = serde_json::to_string(&file).unwrap();
+        let back: File = serde_json::from_str(&json).unwrap();
+        assert_eq!(back.to_source(&config), source);
+        assert_eq!(serde_json::to_string(&back).unwrap(), json);
+
+        let mut lenient = config;
+        lenient.lenient = Some(true);
+        let file = parse(&lenient, "# A\n[ ] ok\n[x] \n- bullet\n");
+        let json = serde_json::to_string(&file).unwrap();
+        let back: File = serde_json::from_str(&json).unwrap();
+        assert_eq!(serde_json::to_string(&back).unwrap(), json);
+        assert!(matches!(
+            back.headings()[0].body[..],
+            [
+                UnderHeading::Todo(_),
+                UnderHeading::Error { .. },
+                UnderHeading::Bullet(_)
+            ]
+        ));
+    }
+
+    #[test]
+    fn collapse_done_folds_done_todos_into_a_count() {
+        let config = config("todo_state = { x = \"x\" }");
+        let mut file = parse(&config, "# A\n[x] a\n[ ] b\n[x] c\ntext\n\n# B\n[ ] d\n");
+        for heading in file.headings_mut() {
+            heading.collapse_done(&config);
+        }
+
+        assert_eq!(
+            file.print(&config),
+            "A\n    [ ] b\n    text\n    ▸ 2 done\n\nB\n    [ ] d\n"
+        );
+    }
+
+    #[test]
+    fn estimates() {
+        let estimate = |description: &str| {
+            Text(vec![TextOp::Normal(description.to_owned())])
+                .estimate()
+                .map(|estimate| estimate.map(|duration| duration.as_secs() / 60))
+        };
+
+        assert_eq!(estimate("task ~30m"), Ok(Some(30)));
+        assert_eq!(estimate("task ~1h30m"), Ok(Some(90)));
+        assert_eq!(estimate("task ~2d"), Ok(Some(2 * 24 * 60)));
+        assert_eq!(estimate("task ~1h ^2024-01-02"), Ok(Some(60)));
+        assert_eq!(estimate("task"), Ok(None));
+        for malformed in ["task ~", "task ~30", "task ~30x", "task ~h", "task ~1h30"] {
+            assert!(estimate(malformed).is_err(), "{malformed}");
+        }
+        assert!(estimate("task ~99999999999999999d").is_err());
+        assert!(estimate("task ~18446744073709551615m").is_err());
+    }
+
+    #[test]
+    fn overflowing_estimate_is_dropped() {
+        let config = config("");
+        let file = parse(&config, "# A\n[ ] task ~99999999999999999d\n");
+        let todo = file.headings()[0].todos()[0];
+        assert!(todo.estimate.is_none());
+    }
+
+    /// Applies an edit replacing `removed` lines from `start` (1-based) with
+    /// `inserted`, then checks `reparse_range` agrees with a full parse.
+    fn check_reparse(source: &str, start: usize, removed: usize, inserted: &[&str]) {
+        let config = config("");
+        let mut lines = source.lines().collect::<Vec<_>>();
+        lines.splice(start - 1..start - 1 + removed, inserted.iter().copied());
+        let edited = lines
+            .iter()
+            .map(|line| format!("{line}\n"))
+            .collect::<String>();
+
+        let case = format!("{source:?} -> {edited:?}");
+        let mut file = parse(&config, source);
+        file.reparse_range(&config, &edited, start..start + inserted.len(), removed)
+            .expect(&case);
+        let full = parse(&config, &edited);
+
+        assert_eq!(file.to_source(&config), full.to_source(&config), "{case}");
+        assert_eq!(
+            file.headings()
+                .iter()
+                .map(|heading| heading.line())
+                .collect::<Vec<_>>(),
+            full.headings()
+                .iter()
+                .map(|heading| heading.line())
+                .collect::<Vec<_>>(),
+            "{case}"
+        );
+    }
+
+    #[test]
+    fn reparse_range_matches_a_full_parse() {
+        let source = "# A\n[ ] a1\n[ ] a2\n\n# B\n[ ] b1\ntext\n\n# C\n[ ] c1\n";
+
+        // inside a section
+        check_reparse(source, 2, 1, &["[x] a1"]);
+        check_reparse(source, 3, 0, &["[ ] new", "- bullet"]);
+        check_reparse(source, 6, 2, &[]);
+        check_reparse(source, 10, 0, &["[ ] c2"]);
+        // headings inserted, deleted and renamed
+        check_reparse(source, 4, 0, &["", "# New", "[ ] n1"]);
+        check_reparse(source, 1, 0, &["# Top", "[ ] t1", ""]);
+        check_reparse(source, 11, 0, &["", "# End"]);
+        check_reparse(source, 4, 2, &[]);
+        check_reparse(source, 4, 4, &[]);
+        check_reparse(source, 5, 1, &["# Renamed"]);
+        check_reparse(source, 9, 1, &["# C2"]);
+        // blank lines
+        check_reparse(source, 4, 1, &[]);
+        check_reparse(source, 4, 0, &["", ""]);
+        check_reparse(source, 8, 1, &["", "", ""]);
+        // emptying and filling the file
+        check_reparse(source, 1, 10, &[]);
+        check_reparse("", 1, 0, &["# A", "[ ] a"]);
+    }
+}
@@ -0,0 +1,171 @@
+use chrono::NaiveDate;
+
+use crate::config::Config;
+
+use super::{
+    parser::{File, Heading, Parse, Text, TextOp, Todo, TodoState},
+    tokenizer::{TokenizeError, Tokens},
+};
+
+/// Heading for tasks that don't belong to any `+project`.
+const DEFAULT_HEADING: &str = "Inbox";
+
+struct Task {
+    done: bool,
+    priority: Option<char>,
+    project: Option<String>,
+    description: String,
+}
+
+fn is_date(word: &str) -> bool {
+    NaiveDate::parse_from_str(word, "%Y-%m-%d").is_ok()
+}
+
+impl Task {
+    fn parse(line: &str) -> Self {
+        let mut words = line.split_whitespace().peekable();
+
+        let done = words.next_if_eq(&"x").is_some();
+        let priority = words
+            .next_if(|word| {
+                let bytes = word.as_bytes();
+                bytes.len() == 3
+                    && bytes[0] == b'('
+                    && bytes[1].is_ascii_uppercase()
+                    && bytes[2] == b')'
+            })
+            .map(|word| word.as_bytes()[1] as char);
+        // a done task's completion date comes before its creation date
+        let mut dates = vec![];
+        while let Some(date) = words.next_if(|word| is_date(word)) {
+            dates.push(date);
+        }
+        let (completed, created) = match (done, dates.as_slice()) {
+            (true, [completed, rest @ ..]) => (Some(*completed), rest.first()),
+            (_, dates) => (None, dates.first()),
+        };
+
+        let mut project = None;
+        let mut description = words
+            .filter(|word| match word.strip_prefix('+') {
+                Some(name) if !name.is_empty() && project.is_none() => {
+                    project = Some(name.to_owned());
+                    false
+                }
+                _ => true,
+            })
+            .map(str::to_owned)
+            .collect::<Vec<_>>();
+        // kept as words the rest of the tool understands, the completion
+        // stamp last as `Todo::set_completed` writes it
+        description.extend(priority.map(|priority| format!("!{priority}")));
+        description.extend(created.map(|created| format!("created:{created}")));
+        description.extend(completed.map(|completed| format!("^{completed}")));
+
+        Self {
+            done,
+            priority,
+            project,
+            description: description.join(" "),
+        }
+    }
+}
+
+/// Builds a `File` out of a todo.txt list. Every task goes under a heading
+/// named after its first `+project` (or `Inbox`), ordered by priority with
+/// unprioritized tasks last. `x` marks a task done and `@context`s and `#tag`s
+/// stay part of the description. The priority is kept as `!A`, the creation
+/// date as `created:YYYY-MM-DD` and the completion date as a `^YYYY-MM-DD`
+/// stamp. Tasks left without a description are skipped.
+///
+/// Errors when the file it builds wouldn't parse back, so a task that can't
+/// be written as a todo never ends up in a file the tool can't read.
+pub fn parse(config: &Config, source: &str) -> Result<File, String> {
+    let mut tasks = source
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(Task::parse)
+        .filter(|task| !task.description.is_empty())
+        .collect::<Vec<_>>();
+    tasks.sort_by_key(|task| task.priority.unwrap_or('['));
+
+    let mut headings: Vec<Heading> = vec![];
+    for task in tasks {
+        let name = task.project.unwrap_or_else(|| DEFAULT_HEADING.to_owned());
+        let index = match headings.iter().position(|heading| heading.name() == name) {
+            Some(index) => index,
+            None => {
+                headings.push(Heading::new(name));
+                headings.len() - 1
+            }
+        };
+
+        let description = Text(vec![TextOp::Normal(task.description)]);
+        headings[index].push_todo(Todo {
+            state: TodoState::resolve(config, if task.done { config.done_marker() } else { "" }),
+            contexts: description.contexts(),
+            estimate: description.estimate().ok().flatten(),
+            anchor: description.anchor(),
+            depends_on: description.depends_on(),
+            completed: description.completed(),
+            number: None,
+            description,
+        });
+    }
+
+    let file: File = headings.into();
+    let tokens: Tokens = file
+        .to_source(config)
+        .parse()
+        .map_err(|err: TokenizeError| format!("Imported tasks don't parse back: {err}"))?;
+    File::parse(config, &mut tokens.to_vecdeque())
+        .map_err(|err| format!("Imported tasks don't parse back: {err}"))?;
+
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn import(source: &str) -> String {
+        let config = Config::load(r#"return { directory = "/tmp" }"#).unwrap();
+        parse(&config, source).unwrap().to_source(&config)
+    }
+
+    #[test]
+    fn priority_and_dates_are_kept() {
+        assert_eq!(
+            import("x (A) 2024-01-03 2024-01-01 ship it +Work @office\n"),
+            "# Work\n[x] ship it @office !A created:2024-01-01 ^2024-01-03\n"
+        );
+        assert_eq!(
+            import("(B) 2024-01-01 plan\n"),
+            "# Inbox\n[ ] plan !B created:2024-01-01\n"
+        );
+
+        let config = Config::load(r#"return { directory = "/tmp" }"#).unwrap();
+        let file = parse(&config, "x 2024-01-03 2024-01-01 done\n").unwrap();
+        assert_eq!(
+            file.headings()[0].todos()[0].completed,
+            NaiveDate::from_ymd_opt(2024, 1, 3)
+        );
+    }
+
+    #[test]
+    fn task_with_only_dates_keeps_them() {
+        assert_eq!(import("x 2024-01-01\n"), "# Inbox\n[x] ^2024-01-01\n");
+        assert_eq!(
+            import("x 2024-01-02 2024-01-01\n"),
+            "# Inbox\n[x] created:2024-01-01 ^2024-01-02\n"
+        );
+    }
+
+    #[test]
+    fn task_without_a_description_is_skipped() {
+        assert_eq!(
+            import("x\n+Work\n(B) call mom +Home\n"),
+            "# Home\n[ ] call mom !B\n"
+        );
+    }
+}
@@ -1,14 +1,17 @@
-use std::{str::FromStr, collections::VecDeque, iter::Peekable};
+use std::{str::FromStr, collections::{BTreeMap, VecDeque}, iter::Peekable, cell::Cell};
+
+use unicode_segmentation::UnicodeSegmentation;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Token {
     BracketOpen,
     Inside(String),
     BracketClose,
-    Heading(String),
+    Heading(String, BTreeMap<String, String>, usize),
     Bullet(TextTokens),
     Text(TextTokens),
     Newline,
+    FrontMatter(BTreeMap<String, String>),
 }
 
 pub struct Tokens(VecDeque<Token>);
@@ -19,82 +22,223 @@ impl Tokens {
     }
 }
 
+#[derive(Debug)]
+pub enum TokenizeError {
+    UnterminatedBracket { line: usize, col: usize },
+    UnterminatedFrontMatter,
+    InvalidFrontMatter { line: usize },
+}
+
+impl TokenizeError {
+    fn position(s: &str, offset: usize) -> (usize, usize) {
+        let before = &s[..offset];
+        let line = before.matches('\n').count() + 1;
+        let col = before.rsplit('\n').next().unwrap_or("").graphemes(true).count() + 1;
+
+        (line, col)
+    }
+}
+
+impl std::fmt::Display for TokenizeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnterminatedBracket { line, col } => {
+                write!(f, "Unterminated `[` at line {line}, column {col}")
+            }
+            Self::UnterminatedFrontMatter => write!(f, "Front matter without a closing `---`"),
+            Self::InvalidFrontMatter { line } => {
+                write!(f, "Expected `key: value` in the front matter at line {line}")
+            }
+        }
+    }
+}
+
 impl FromStr for Tokens {
-    type Err = String;
+    type Err = TokenizeError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        log::debug!("tokenizing {} bytes", s.len());
         let mut tokens = VecDeque::new();
-        let mut chars = s.chars().peekable();
-
-        while let Some(char) = chars.peek() {
-            match char {
-                '[' => {
-                    chars.next();
+        let (front_matter, start) = front_matter(s)?;
+        if start > 0 {
+            tokens.push_back(Token::FrontMatter(front_matter));
+        }
+        // Iterating over grapheme clusters keeps combining marks and emoji
+        // modifiers attached to their base, so a marker like `*` followed by
+        // a combining accent is plain text rather than the start of a span.
+        // `\r\n` is a single cluster, so it's folded into a plain newline.
+        let offset = Cell::new(start);
+        let line = Cell::new(s[..start].matches('\n').count() + 1);
+        let mut graphemes = s[start..]
+            .graphemes(true)
+            .inspect(|grapheme| {
+                offset.set(offset.get() + grapheme.len());
+                if grapheme.ends_with('\n') {
+                    line.set(line.get() + 1);
+                }
+            })
+            .map(|grapheme| if grapheme == "\r\n" { "\n" } else { grapheme })
+            .peekable();
+
+        while let Some(grapheme) = graphemes.peek() {
+            match *grapheme {
+                "[" => {
+                    let start = offset.get() - 1;
+                    graphemes.next();
                     tokens.push_back(Token::BracketOpen);
                     let mut inside = vec![];
 
-                    while let Some(' ') = chars.peek() {
-                        chars.next();
+                    while let Some(&" " | &"\t") = graphemes.peek() {
+                        graphemes.next();
                     }
                     
-                    while let Some(char) = chars.next() {
-                        if char == ']' {
-                            break;
+                    loop {
+                        match graphemes.next() {
+                            Some("]") => break,
+                            // `\]` and `\\` for a literal `]` or `\` in a state
+                            Some("\\") if matches!(graphemes.peek(), Some(&"]" | &"\\")) => {
+                                inside.push(graphemes.next().unwrap())
+                            }
+                            Some("\n") | None => {
+                                let (line, col) = TokenizeError::position(s, start);
+                                return Err(TokenizeError::UnterminatedBracket { line, col });
+                            }
+                            Some(grapheme) => inside.push(grapheme),
                         }
-
-                        inside.push(char);
                     }
 
-                    tokens.push_back(Token::Inside(inside.into_iter().collect()));
+                    tokens.push_back(Token::Inside(inside.concat()));
                     tokens.push_back(Token::BracketClose);
                 },
-                '#' => {
-                    chars.next();
+                "#" => {
+                    let line = line.get();
+                    graphemes.next();
                     let mut heading = vec![];
 
-                    while let Some(' ') = chars.peek() {
-                        chars.next();
+                    while let Some(&" " | &"\t") = graphemes.peek() {
+                        graphemes.next();
                     }
 
-                    while let Some(char) = chars.next() {
-                        if char == '\n' {
-                            tokens.push_back(Token::Heading(heading.into_iter().collect()));
-                            tokens.push_back(Token::Newline);
+                    for grapheme in graphemes.by_ref() {
+                        if grapheme == "\n" {
                             break;
                         }
 
-                        heading.push(char);
+                        heading.push(grapheme);
                     }
+
+                    // ended by the newline, or by the end of a file that
+                    // doesn't have one
+                    let (name, attributes) = heading_attributes(heading.concat());
+                    tokens.push_back(Token::Heading(name, attributes, line));
+                    tokens.push_back(Token::Newline);
                 }
-                '\n' => {
-                    chars.next();
+                "\n" => {
+                    graphemes.next();
                     tokens.push_back(Token::Newline)
                 },
-                '-' => {
-                    chars.next();
-                    while let Some(' ') = chars.peek() {
-                        chars.next();
+                "-" => {
+                    graphemes.next();
+                    while let Some(&" " | &"\t") = graphemes.peek() {
+                        graphemes.next();
                     }
                     
-                    tokens.push_back(Token::Bullet(TextTokens::from_iter(&mut chars)))
+                    tokens.push_back(Token::Bullet(TextTokens::from_iter(&mut graphemes)))
                 },
-                ' ' => {
-                    chars.next();
+                " " | "\t" => {
+                    graphemes.next();
+                },
+                "\\" => {
+                    graphemes.next();
+                    // `\[`, `\#` and `\-` escape the markers that would
+                    // otherwise start a todo, heading or bullet
+                    let literal = match graphemes.peek() {
+                        Some(&marker) if LINE_MARKERS.contains(&marker) => {
+                            graphemes.next();
+                            marker
+                        }
+                        _ => "\\",
+                    };
+                    let mut text = TextTokens::from_iter(&mut graphemes);
+
+                    match text.0.front_mut() {
+                        Some(TextToken::Text(rest)) => rest.insert_str(0, literal),
+                        _ => text.0.push_front(TextToken::Text(literal.to_owned())),
+                    }
+                    tokens.push_back(Token::Text(text))
                 },
                 _ => {
-                    while let Some(' ') = chars.peek() {
-                        chars.next();
+                    while let Some(&" " | &"\t") = graphemes.peek() {
+                        graphemes.next();
                     }
                     
-                    tokens.push_back(Token::Text(TextTokens::from_iter(&mut chars)))
+                    tokens.push_back(Token::Text(TextTokens::from_iter(&mut graphemes)))
                 }
             }
         }
 
+        log::debug!("tokenized into {} tokens", tokens.len());
         return Ok(Self(tokens));
     }
 }
 
+/// The `key: value` lines of the front matter a file can start with, fenced
+/// by `---` lines, and the offset the block ends at, 0 without one.
+fn front_matter(s: &str) -> Result<(BTreeMap<String, String>, usize), TokenizeError> {
+    let mut front_matter = BTreeMap::new();
+    let mut lines = s.split_inclusive('\n');
+    let Some(first) = lines.next().filter(|line| line.trim_end() == "---") else {
+        return Ok((front_matter, 0));
+    };
+
+    let mut end = first.len();
+    for (i, line) in lines.enumerate() {
+        end += line.len();
+        let line = line.trim();
+        if line == "---" {
+            return Ok((front_matter, end));
+        } else if line.is_empty() {
+            continue;
+        }
+
+        let Some((key, value)) = line.split_once(':') else {
+            return Err(TokenizeError::InvalidFrontMatter { line: i + 2 });
+        };
+        front_matter.insert(key.trim().to_owned(), value.trim().to_owned());
+    }
+
+    Err(TokenizeError::UnterminatedFrontMatter)
+}
+
+/// Graphemes that have a meaning of their own at the start of a line.
+pub const LINE_MARKERS: [&str; 3] = ["[", "#", "-"];
+
+/// Splits a trailing `{key=value, ...}` block off a heading, leaving the name
+/// untouched when the block is missing or malformed.
+fn heading_attributes(heading: String) -> (String, BTreeMap<String, String>) {
+    let trimmed = heading.trim_end();
+    let Some((name, attributes)) = trimmed
+        .strip_suffix('}')
+        .and_then(|rest| rest.rsplit_once('{'))
+    else {
+        return (heading, BTreeMap::new());
+    };
+
+    let attributes = attributes
+        .split(',')
+        .filter(|pair| !pair.trim().is_empty())
+        .map(|pair| {
+            pair.split_once('=')
+                .map(|(key, value)| (key.trim().to_owned(), value.trim().to_owned()))
+        })
+        .collect::<Option<BTreeMap<_, _>>>();
+
+    match attributes {
+        Some(attributes) if !attributes.is_empty() => (name.trim_end().to_owned(), attributes),
+        _ => (heading, BTreeMap::new()),
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum TextToken {
     Verbatim(Vec<TextToken>),
@@ -103,120 +247,175 @@ pub enum TextToken {
     Bold(Vec<TextToken>),
     Italic(Vec<TextToken>),
     TextExtra(char, Vec<TextToken>),
+    Colored(String, Vec<TextToken>),
     Text(String),
 }
 
 impl TextToken {
-    fn from_iter<I: Iterator<Item = char>>(iter: &mut Peekable<I>) -> Self {
-        match iter.peek().unwrap() {
-            '\n' => return Self::Text(format!("")),
-            '`' => {
+    fn from_iter<'a, I: Iterator<Item = &'a str>>(iter: &mut Peekable<I>) -> Self {
+        match *iter.peek().unwrap() {
+            "\n" => return Self::Text(format!("")),
+            "`" => {
                 iter.next();
                 let mut ret = vec![Self::from_iter(iter)];
 
-                while let Some(char) = iter.peek() {
-                    if *char == '\n' {
+                while let Some(grapheme) = iter.peek() {
+                    if *grapheme == "\n" {
                         return Self::TextExtra('`', ret);
-                    } else if *char == '`' {
+                    } else if *grapheme == "`" {
                         iter.next();
                         break;
-                    } else if ['_', '-', '*', '/'].contains(char) {
-                        let ch = *char;
+                    } else if ["_", "-", "*", "/"].contains(grapheme) {
+                        let ch = grapheme.chars().next().unwrap();
                         let token = Self::from_iter(iter);
 
                         if matches!(&token, Self::Text(text) if text.is_empty()) {
                             ret.push(Self::TextExtra(ch, vec![]))
                         }
                         ret.push(token);
+                    } else {
+                        ret.push(Self::from_iter(iter));
                     }
                 }
 
                 return Self::Verbatim(ret);
             },
-            '_' => {
+            "_" => {
                 iter.next();
                 let mut ret = vec![Self::from_iter(iter)];
 
-                while let Some(char) = iter.peek() {
-                    if *char == '\n' {
+                while let Some(grapheme) = iter.peek() {
+                    if *grapheme == "\n" {
                         return Self::TextExtra('_', ret);
-                    } else if *char == '_' {
+                    } else if *grapheme == "_" {
                         iter.next();
                         break;
-                    } else if ['`', '-', '*', '/'].contains(char) {
+                    } else {
                         ret.push(Self::from_iter(iter));
                     }
                 }
 
                 return Self::Underline(ret);
             },
-            '-' => {
+            "-" => {
                 iter.next();
                 let mut ret = vec![Self::from_iter(iter)];
 
-                while let Some(char) = iter.peek() {
-                    if *char == '\n' {
+                while let Some(grapheme) = iter.peek() {
+                    if *grapheme == "\n" {
                         return Self::TextExtra('-', ret);
-                    } else if *char == '-' {
+                    } else if *grapheme == "-" {
                         iter.next();
                         break;
-                    } else if ['`', '_', '*', '/'].contains(char) {
+                    } else {
                         ret.push(Self::from_iter(iter));
                     }
                 }
 
                 return Self::Crossed(ret);
             },
-            '*' => {
+            "*" => {
                 iter.next();
                 let mut ret = vec![Self::from_iter(iter)];
 
-                while let Some(char) = iter.peek() {
-                    if *char == '\n' {
+                while let Some(grapheme) = iter.peek() {
+                    if *grapheme == "\n" {
                         return Self::TextExtra('*', ret);
-                    } else if *char == '*' {
+                    } else if *grapheme == "*" {
                         iter.next();
                         break;
-                    } else if ['`', '_', '-', '/'].contains(char) {
+                    } else {
                         ret.push(Self::from_iter(iter));
                     }
                 }
 
                 return Self::Bold(ret);
             },
-            '/' => {
+            "/" => {
                 iter.next();
                 let mut ret = vec![Self::from_iter(iter)];
 
-                while let Some(char) = iter.peek() {
-                    if *char == '\n' {
+                while let Some(grapheme) = iter.peek() {
+                    if *grapheme == "\n" {
                         return Self::TextExtra('/', ret);
-                    } else if *char == '/' {
+                    } else if *grapheme == "/" {
                         iter.next();
                         break;
-                    } else if ['`', '_', '-', '*'].contains(char) {
+                    } else {
                         ret.push(Self::from_iter(iter));
                     }
                 }
 
                 return Self::Italic(ret);
             },
-            _ => {
-                let mut text = vec![iter.next().unwrap()];
-                while let Some(char) = iter.peek() {
-                    if ['`', '_', '-', '*', '/', '\n'].contains(char) {
+            "{" => {
+                iter.next();
+                let mut color = String::new();
+
+                loop {
+                    match iter.peek() {
+                        Some(&":") => {
+                            iter.next();
+                            break;
+                        }
+                        Some(&"}" | &"\n" | &" ") | None => return Self::Text(format!("{{{color}")),
+                        Some(_) => color.push_str(iter.next().unwrap()),
+                    }
+                }
+                let mut ret = vec![];
+
+                while let Some(grapheme) = iter.peek() {
+                    if *grapheme == "\n" {
+                        ret.insert(0, Self::Text(format!("{color}:")));
+                        return Self::TextExtra('{', ret);
+                    } else if *grapheme == "}" {
+                        iter.next();
                         break;
+                    } else {
+                        ret.push(Self::from_iter(iter));
+                    }
+                }
+
+                Self::Colored(color, ret)
+            },
+            _ => {
+                let mut text = vec![];
+                loop {
+                    let grapheme = iter.next().unwrap();
+                    text.push(grapheme);
+                    if grapheme == "^" {
+                        text.extend(stamp_date(iter));
                     }
 
-                    text.push(iter.next().unwrap())
+                    match iter.peek() {
+                        Some(grapheme) if !["`", "_", "-", "*", "/", "{", "}", "\n"].contains(grapheme) => {}
+                        _ => break,
+                    }
                 }
 
-                return Self::Text(text.into_iter().collect());
+                return Self::Text(text.concat());
             }
         }
     }
 }
 
+/// Takes the `YYYY-MM-DD` following the `^` of a completion stamp, so its
+/// dashes aren't read as strikethrough.
+fn stamp_date<'a, I: Iterator<Item = &'a str>>(iter: &mut Peekable<I>) -> Vec<&'a str> {
+    let mut date = vec![];
+    for expected in "0000-00-00".chars() {
+        let Some(grapheme) = iter.next_if(|grapheme| match expected {
+            '-' => *grapheme == "-",
+            _ => grapheme.len() == 1 && grapheme.as_bytes()[0].is_ascii_digit(),
+        }) else {
+            break;
+        };
+        date.push(grapheme);
+    }
+
+    date
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct TextTokens(VecDeque<TextToken>);
 
@@ -225,12 +424,12 @@ impl TextTokens {
         self.0
     }
 
-    fn from_iter<I: Iterator<Item = char>>(iter: &mut Peekable<I>) -> Self {
+    fn from_iter<'a, I: Iterator<Item = &'a str>>(iter: &mut Peekable<I>) -> Self {
         let mut tokens = VecDeque::new();
 
-        while let Some(char) = iter.peek() {
-            match char {
-                '\n' => return Self(tokens),
+        while let Some(grapheme) = iter.peek() {
+            match *grapheme {
+                "\n" => return Self(tokens),
                 _ => tokens.push_back(TextToken::from_iter(iter))
             }
         }
@@ -238,3 +437,103 @@ impl TextTokens {
         return Self(tokens);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokenize(source: &str) -> Vec<Token> {
+        source.parse::<Tokens>().unwrap().to_vecdeque().into()
+    }
+
+    fn text(tokens: Vec<TextToken>) -> Token {
+        Token::Text(TextTokens(tokens.into()))
+    }
+
+    #[test]
+    fn combining_mark_after_marker_is_text() {
+        assert_eq!(
+            tokenize("x *\u{301}y\n"),
+            [text(vec![TextToken::Text("x *\u{301}y".to_owned())]), Token::Newline]
+        );
+    }
+
+    #[test]
+    fn emoji_with_modifiers_stay_whole() {
+        assert_eq!(
+            tokenize("👍🏽 👩‍💻 *ok*\n"),
+            [
+                text(vec![
+                    TextToken::Text("👍🏽 👩‍💻 ".to_owned()),
+                    TextToken::Bold(vec![TextToken::Text("ok".to_owned())]),
+                ]),
+                Token::Newline,
+            ]
+        );
+    }
+
+    #[test]
+    fn combining_marks_in_headings_and_states() {
+        assert_eq!(
+            tokenize("# Cafe\u{301}\n[e\u{301}] a\u{308}\n"),
+            [
+                Token::Heading("Cafe\u{301}".to_owned(), BTreeMap::new(), 1),
+                Token::Newline,
+                Token::BracketOpen,
+                Token::Inside("e\u{301}".to_owned()),
+                Token::BracketClose,
+                text(vec![TextToken::Text("a\u{308}".to_owned())]),
+                Token::Newline,
+            ]
+        );
+    }
+
+    #[test]
+    fn escaped_bracket_is_text() {
+        assert_eq!(
+            tokenize("[ ] task\n\\[literal]\n"),
+            [
+                Token::BracketOpen,
+                Token::Inside(String::new()),
+                Token::BracketClose,
+                text(vec![TextToken::Text("task".to_owned())]),
+                Token::Newline,
+                text(vec![TextToken::Text("[literal]".to_owned())]),
+                Token::Newline,
+            ]
+        );
+    }
+
+    #[test]
+    fn crlf_is_a_newline() {
+        assert_eq!(
+            tokenize("x\r\ny\r\n"),
+            [
+                text(vec![TextToken::Text("x".to_owned())]),
+                Token::Newline,
+                text(vec![TextToken::Text("y".to_owned())]),
+                Token::Newline,
+            ]
+        );
+    }
+
+    #[test]
+    fn tabs_indent_like_spaces() {
+        assert_eq!(tokenize("\t[ ]\ttask\n"), tokenize("    [ ] task\n"));
+        assert_eq!(tokenize("#\tTitle\n"), tokenize("# Title\n"));
+        assert_eq!(tokenize("\t-\tbullet\n"), tokenize("  - bullet\n"));
+    }
+
+    #[test]
+    fn escapes_inside_brackets() {
+        assert_eq!(
+            tokenize("[a\\]b\\\\] x\n")[..3],
+            [
+                Token::BracketOpen,
+                Token::Inside("a]b\\".to_owned()),
+                Token::BracketClose,
+            ]
+        );
+        assert_eq!(tokenize("[a\\b] x\n")[1], Token::Inside("a\\b".to_owned()));
+    }
+}
@@ -0,0 +1,72 @@
+use std::path::{Path, PathBuf};
+
+use crate::{cache::file_key, config::Config};
+
+/// Earlier versions of `file` in `$XDG_DATA_HOME/todo/history`, one file per
+/// version named by when it was replaced, oldest first.
+fn versions(file: &Path) -> Option<(PathBuf, Vec<PathBuf>)> {
+    let directory = xdg::BaseDirectories::with_prefix("todo")
+        .ok()?
+        .get_data_home()
+        .join("history")
+        .join(file_key(file)?);
+
+    let mut versions = std::fs::read_dir(&directory)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    versions.sort();
+
+    Some((directory, versions))
+}
+
+/// Keeps the current contents of `file` before it's overwritten or removed,
+/// dropping the oldest versions past `undo_depth`.
+pub fn save(config: &Config, file: &Path) {
+    let Ok(contents) = std::fs::read(file) else {
+        return;
+    };
+    let Some((directory, versions)) = versions(file) else {
+        return;
+    };
+    if versions
+        .last()
+        .is_some_and(|latest| std::fs::read(latest).is_ok_and(|latest| latest == contents))
+    {
+        return;
+    }
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    if std::fs::create_dir_all(&directory).is_err()
+        || crate::atomic::write(&directory.join(format!("{now:020}")), contents).is_err()
+    {
+        return;
+    }
+
+    let excess = (versions.len() + 1).saturating_sub(config.undo_depth());
+    for version in versions.iter().take(excess) {
+        let _ = std::fs::remove_file(version);
+    }
+}
+
+/// Swaps `file` with its most recent earlier version, so undoing twice
+/// brings the change back. Returns the contents before and after, `None`
+/// when there's nothing to undo.
+pub fn undo(file: &Path) -> Option<(Vec<u8>, Vec<u8>)> {
+    let (_, versions) = versions(file)?;
+    let latest = versions.last()?;
+
+    let previous = std::fs::read(latest).ok()?;
+    let current = std::fs::read(file).unwrap_or_default();
+    crate::atomic::write(latest, &current).ok()?;
+    crate::atomic::write(file, &previous).ok()?;
+
+    Some((current, previous))
+}
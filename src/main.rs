@@ -1,15 +1,28 @@
-use std::{fmt::Display, process::Stdio};
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    io::{BufRead, IsTerminal, Read, Write},
+    path::{Path, PathBuf},
+    process::Stdio,
+};
 
-use chrono::{Duration, Local};
-use clap::{Parser, Subcommand, ValueEnum};
+use cache::Summary;
+use chrono::{Duration, Local, NaiveDate};
+use clap::{ArgGroup, CommandFactory, Parser, Subcommand, ValueEnum};
 use config::Config;
 use file_format::{
     parser::{self, Parse},
-    tokenizer::Tokens,
+    todo_txt,
+    tokenizer::{TokenizeError, Tokens, LINE_MARKERS},
 };
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use regex::{NoExpand, Regex};
 
+mod atomic;
+mod cache;
 mod config;
 mod file_format;
+mod history;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -17,11 +30,37 @@ struct Args {
     #[arg(value_enum)]
     day: Option<Day>,
     #[arg(short, long)]
-    file: Option<String>,
+    file: Vec<String>,
+    #[arg(long, global = true)]
+    minified: bool,
+    #[arg(long, global = true, value_enum, default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
     #[command(subcommand)]
     command: Command,
 }
 
+/// When to style the output with ANSI escapes.
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum ColorChoice {
+    Always,
+    /// When stdout is a terminal and `NO_COLOR` isn't set
+    Auto,
+    Never,
+}
+
+impl ColorChoice {
+    fn enabled(self) -> bool {
+        match self {
+            Self::Always => true,
+            Self::Auto => {
+                std::env::var_os("NO_COLOR").is_none_or(|no_color| no_color.is_empty())
+                    && std::io::stdout().is_terminal()
+            }
+            Self::Never => false,
+        }
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
 enum Day {
     #[value(name = "y")]
@@ -46,143 +85,2289 @@ impl Display for Day {
     }
 }
 
-#[derive(Subcommand)]
+/// What `Export` renders the vault as.
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum Format {
+    /// The parsed files, what `Import` reads back
+    Json,
+    /// The todos of every file, as for eww widgets
+    Eww,
+    /// Every file as `Show` prints it
+    Plain,
+    /// One JSON object per todo per line, written as the files are read
+    Jsonl,
+}
+
+/// A todo as a line of the `jsonl` export.
+#[derive(serde::Serialize)]
+struct TodoLine<'a> {
+    file: &'a str,
+    heading: &'a str,
+    state: String,
+    text: String,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    tags: &'a [String],
+}
+
+impl Day {
+    fn date(&self) -> NaiveDate {
+        let today = Local::now().date_naive();
+
+        match self {
+            Self::Yesterday => today - Duration::days(1),
+            Self::Today => today,
+            Self::Tomorrow => today + Duration::days(1),
+        }
+    }
+}
+
+#[derive(Subcommand, Clone)]
 enum Command {
     New {
         #[arg(short, long)]
         editor: bool,
+        #[arg(short, long)]
+        pick: bool,
+        #[arg(long = "template-var", value_name = "KEY=VALUE", value_parser = template_var)]
+        vars: Vec<(String, String)>,
     },
-    Edit,
-    Show,
+    Edit {
+        #[arg(short, long, conflicts_with = "heading")]
+        line: Option<usize>,
+        #[arg(long)]
+        heading: Option<String>,
+    },
+    Show(ShowArgs),
     Raw,
-    EwwShow,
-    Config,
+    EwwShow {
+        #[arg(short, long)]
+        summary: bool,
+        #[arg(long, conflicts_with = "summary")]
+        summary_only: bool,
+    },
+    Add {
+        heading: String,
+        description: String,
+    },
+    Note {
+        text: String,
+    },
+    Complete {
+        query: String,
+        #[arg(short, long)]
+        all: bool,
+    },
+    Toggle {
+        /// 1-based, counting todos heading after heading in file order, as
+        /// printed by `show --numbers`
+        index: usize,
+    },
+    Merge,
+    Replace {
+        from: String,
+        to: String,
+        #[arg(long)]
+        regex: bool,
+        #[arg(long)]
+        dry_run: bool,
+        #[arg(long)]
+        all: bool,
+    },
+    #[command(group(ArgGroup::new("age").required(true)))]
+    Prune {
+        #[arg(long, group = "age")]
+        older_than: Option<u32>,
+        #[arg(long, group = "age")]
+        all: bool,
+        #[arg(long)]
+        vault: bool,
+    },
+    Migrate {
+        #[arg(long)]
+        vault: bool,
+        #[arg(long)]
+        dry_run: bool,
+    },
+    Clean {
+        #[arg(long)]
+        drop_done: bool,
+        #[arg(long)]
+        remove_empty_files: bool,
+    },
+    Count {
+        #[arg(short, long)]
+        bar: bool,
+    },
+    Toc {
+        #[arg(short, long)]
+        jump: Option<usize>,
+    },
+    Graph,
+    Undo,
+    Watch {
+        #[arg(short, long, default_value_t = 500)]
+        interval: u64,
+    },
+    Export {
+        #[arg(short, long, value_enum, default_value_t = Format::Json)]
+        format: Format,
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    #[command(group(ArgGroup::new("format").required(true)))]
+    Import {
+        #[arg(long, group = "format")]
+        json: bool,
+        #[arg(short, long)]
+        force: bool,
+        input: Option<PathBuf>,
+    },
+    /// Import a todo.txt list, one heading per `+project`. `(A)` priorities
+    /// are kept as `!A`, creation dates as `created:YYYY-MM-DD` and completion
+    /// dates as `^YYYY-MM-DD` stamps
+    ImportTxt {
+        #[arg(short, long)]
+        force: bool,
+        input: PathBuf,
+    },
+    Config {
+        #[arg(long)]
+        validate: bool,
+    },
+    /// Write a starter config and create the todo directory
+    Init {
+        /// Overwrite an existing config
+        #[arg(short, long)]
+        force: bool,
+    },
 }
 
-fn main() {
-    let arg = Args::parse();
-    let config = Config::get().unwrap();
-    let mut file = (&config.directory).clone();
-    let day = if let Some(day) = arg.day {
-        file.push(
-            match day {
-                Day::Yesterday => Local::now() - Duration::days(1),
-                Day::Today => Local::now(),
-                Day::Tomorrow => Local::now() + Duration::days(1),
-            }
-            .format("%d%m%Y.todo")
-            .to_string(),
-        );
-        true
-    } else if let Some(file_) = arg.file {
-        file.push(file_ + ".todo");
-        false
-    } else {
-        return;
-    };
-    let exists = std::path::Path::new(&file).exists();
+impl Command {
+    /// Whether the command works on the given file, and so runs once for each
+    /// of several. The rest cover the whole vault, or like `watch` never
+    /// return, and take at most one `--file`.
+    fn per_file(&self) -> bool {
+        !matches!(
+            self,
+            Self::Show(ShowArgs { since: Some(_), .. })
+                | Self::Prune { vault: true, .. }
+                | Self::Migrate { vault: true, .. }
+                | Self::Watch { .. }
+                | Self::Export { .. }
+                | Self::Import { .. }
+                | Self::Config { .. }
+        )
+    }
+}
 
-    match arg.command {
-        Command::New { .. } if day && exists && arg.day.is_some() => {
-            eprintln!("Todo for {} already exists", arg.day.unwrap())
+#[derive(clap::Args, Clone)]
+struct ShowArgs {
+    #[arg(long, conflicts_with = "only_done")]
+    only_open: bool,
+    #[arg(long)]
+    only_done: bool,
+    #[arg(long)]
+    hide_empty: bool,
+    /// Show done todos instead of folding each heading's into a "▸ N done"
+    /// line
+    #[arg(long, conflicts_with = "only_done")]
+    show_done: bool,
+    /// Only show todos, leaving out text, bullets and the headings that end
+    /// up empty
+    #[arg(long)]
+    todos_only: bool,
+    /// Keep the headings `--todos-only` leaves empty
+    #[arg(long, requires = "todos_only")]
+    keep_empty: bool,
+    #[arg(short, long)]
+    context: Option<String>,
+    #[arg(long, conflicts_with_all = ["since_heading", "heading_glob"])]
+    heading: Option<String>,
+    /// Only show the headings whose names match a glob, like 'Project *'
+    #[arg(long, value_name = "GLOB")]
+    heading_glob: Option<String>,
+    #[arg(long)]
+    since_heading: Option<String>,
+    #[arg(long, conflicts_with = "group_by_state")]
+    flat: bool,
+    #[arg(long, conflicts_with_all = ["flat", "group_by_state"])]
+    budget: bool,
+    #[arg(long)]
+    group_by_state: bool,
+    /// List the todos under each `@context` they carry, across headings
+    #[arg(long, conflicts_with_all = ["flat", "group_by_state", "budget"])]
+    group_by_tag: bool,
+    #[arg(long)]
+    columns: Option<usize>,
+    #[arg(long, value_name = "N")]
+    max_todos: Option<usize>,
+    #[arg(long)]
+    sort_headings: bool,
+    #[arg(long, value_name = "BOOL")]
+    wrap_code: Option<bool>,
+    #[arg(long)]
+    compact: bool,
+    #[arg(long)]
+    indent: Option<usize>,
+    #[arg(long)]
+    width: Option<usize>,
+    /// Take the width from an environment variable when it's set, if
+    /// `--width` isn't given
+    #[arg(long, value_name = "VAR", num_args = 0..=1, default_missing_value = "TODO_WIDTH")]
+    width_from: Option<String>,
+    #[arg(long)]
+    lenient: bool,
+    #[arg(long, value_name = "DATE")]
+    since: Option<NaiveDate>,
+    #[arg(long, value_name = "WORD")]
+    highlight: Vec<String>,
+    /// Show the file's front matter above its headings
+    #[arg(long)]
+    front_matter: bool,
+    /// Prefix todos with the index `toggle` takes for them, numbered before
+    /// any filtering
+    #[arg(long)]
+    numbers: bool,
+}
+
+impl ShowArgs {
+    /// Whether done todos are folded into a count. Only headings have a place
+    /// for the count, so the flat and grouped views always show them.
+    fn collapse_done(&self) -> bool {
+        !(self.show_done
+            || self.only_done
+            || self.flat
+            || self.budget
+            || self.group_by_state
+            || self.group_by_tag)
+    }
+
+    /// Applies the flags that override rendering options from the config.
+    fn apply(&self, config: &mut Config) {
+        if let Some(indent) = self.indent {
+            config.body_indent = Some(indent);
         }
-        Command::New { editor: true } => {
-            let template = if !day {
-                "".to_string()
-            } else if let Some(template) = &config.template {
-                let template = std::fs::read_to_string(&template).unwrap();
-                template
-            } else {
-                "".to_string()
-            };
-            std::fs::write(&file, template).unwrap();
-
-            if let Some(editor) = &config.editor {
-                std::process::Command::new(&editor)
-                    .arg(&file)
-                    .stdin(Stdio::inherit())
-                    .stdout(Stdio::inherit())
-                    .stderr(Stdio::inherit())
-                    .output()
-                    .unwrap();
-            } else {
-                edit::edit_file(&file).unwrap();
-            }
+        if let Some(width) = self
+            .width
+            .or_else(|| env_width(self.width_from.as_deref()?))
+        {
+            config.wrap_width = Some(width);
         }
-        Command::New { .. } => {
-            std::fs::write(
-                &file,
-                if !day {
-                    "".to_string()
-                } else if let Some(template) = &config.template {
-                    let template = std::fs::read_to_string(&template).unwrap();
-                    template
-                } else {
-                    "".to_string()
-                },
-            )
-            .unwrap();
+        if let Some(wrap_code) = self.wrap_code {
+            config.wrap_code = Some(wrap_code);
         }
-        Command::Edit if exists => {
-            if let Some(editor) = &config.editor {
-                std::process::Command::new(&editor)
-                    .arg(&file)
-                    .stdin(Stdio::inherit())
-                    .stdout(Stdio::inherit())
-                    .stderr(Stdio::inherit())
-                    .output()
-                    .unwrap();
-            } else {
-                edit::edit_file(&file).unwrap();
+        if self.compact {
+            config.compact = Some(true);
+        }
+        if self.lenient {
+            config.lenient = Some(true);
+        }
+        config.highlight = self
+            .highlight
+            .iter()
+            .map(|word| Regex::new(&format!("(?i){}", regex::escape(word))).unwrap())
+            .collect();
+    }
+}
+
+/// The width in the environment variable `var`, `None` when it's unset.
+fn env_width(var: &str) -> Option<usize> {
+    let width = std::env::var(var).ok()?;
+
+    match width.trim().parse() {
+        Ok(width) => Some(width),
+        Err(_) => {
+            eprintln!("{var} isn't a width: \"{width}\"");
+            std::process::exit(1);
+        }
+    }
+}
+
+fn show(config: &Config, mut file: parser::File, args: &ShowArgs) -> Result<String, String> {
+    if let Some(name) = &args.heading {
+        let lowercase = name.to_lowercase();
+        file.headings_mut()
+            .retain(|heading| heading.name().to_lowercase() == lowercase);
+        if file.headings().is_empty() {
+            return Err(format!("No heading named \"{name}\""));
+        }
+    }
+    if let Some(glob) = &args.heading_glob {
+        let pattern =
+            glob::Pattern::new(glob).map_err(|err| format!("Invalid glob \"{glob}\": {err}"))?;
+        let options = glob::MatchOptions {
+            case_sensitive: false,
+            ..Default::default()
+        };
+        file.headings_mut()
+            .retain(|heading| pattern.matches_with(heading.name().trim(), options));
+        if file.headings().is_empty() {
+            return Err(format!("No heading matches \"{glob}\""));
+        }
+    }
+    if let Some(name) = &args.since_heading {
+        let lowercase = name.to_lowercase();
+        let Some(index) = file
+            .headings()
+            .iter()
+            .position(|heading| heading.name().to_lowercase() == lowercase)
+        else {
+            return Err(format!("No heading named \"{name}\""));
+        };
+        file.headings_mut().drain(..index);
+    }
+
+    for heading in file.headings_mut() {
+        if args.only_open {
+            heading.retain_todos(|todo| !config.is_done(&todo.state));
+        } else if args.only_done {
+            heading.retain_todos(|todo| config.is_done(&todo.state));
+        }
+        if let Some(context) = &args.context {
+            heading.retain_todos(|todo| todo.contexts.contains(context));
+        }
+        if args.todos_only {
+            heading.retain_only_todos();
+        }
+        // with only todos shown, there's no line for the count to go on
+        if args.collapse_done() && args.todos_only {
+            heading.retain_todos(|todo| !config.is_done(&todo.state));
+        } else if args.collapse_done() {
+            heading.collapse_done(config);
+        }
+        if let Some(max) = args.max_todos {
+            heading.truncate_todos(max);
+        }
+    }
+    if args.hide_empty || (args.todos_only && !args.keep_empty) {
+        file.headings_mut().retain(|heading| !heading.is_empty());
+    }
+    if args.sort_headings {
+        sort_dated_headings(&mut file);
+    }
+
+    if args.budget {
+        return Ok(budget(config, &file));
+    }
+
+    if args.group_by_state {
+        // `state_order` first, then open, in progress, done: the order of
+        // `state_cycle`, with states outside of it going before the done ones
+        let rank = |todo: &parser::Todo| {
+            let source = todo.state.to_source(config);
+            (
+                config.state_rank(&todo.state),
+                config
+                    .state_cycle
+                    .iter()
+                    .position(|state| state.trim() == source.trim())
+                    .unwrap_or(config.state_cycle.len() + usize::from(config.is_done(&todo.state))),
+            )
+        };
+
+        let mut groups = BTreeMap::<_, Vec<_>>::new();
+        for heading in file.headings() {
+            for todo in heading.todos() {
+                groups
+                    .entry((rank(todo), todo.state.to_source(config)))
+                    .or_default()
+                    .push((heading.name(), todo));
             }
         }
-        Command::Show if exists => {
-            let tokens: Tokens = std::fs::read_to_string(&file).unwrap().parse().unwrap();
-            let mut vecdeque = tokens.to_vecdeque();
 
-            print!(
-                "{}",
-                match parser::File::parse(&config, &mut vecdeque) {
-                    Ok(ok) => ok.print(&config),
-                    Err(err) => err.to_string(),
+        let indent = " ".repeat(config.body_indent());
+        return Ok(groups
+            .values()
+            .map(|todos| {
+                todos.iter().fold(
+                    format!("{}\n", todos[0].1.state.print(config)),
+                    |buf, (heading, todo)| {
+                        format!("{buf}{indent}{}  ({heading})\n", todo.print(config))
+                    },
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n"));
+    }
+
+    if args.group_by_tag {
+        let mut groups = BTreeMap::<_, Vec<_>>::new();
+        let mut untagged = vec![];
+        for heading in file.headings() {
+            for todo in heading.todos() {
+                if todo.contexts.is_empty() {
+                    untagged.push((heading.name(), todo));
                 }
-            );
+                for context in &todo.contexts {
+                    groups
+                        .entry(format!("@{context}"))
+                        .or_default()
+                        .push((heading.name(), todo));
+                }
+            }
+        }
+        if !untagged.is_empty() {
+            groups.insert("no tag".to_owned(), untagged);
         }
-        Command::Raw if exists => {
-            let tokens: Tokens = std::fs::read_to_string(&file).unwrap().parse().unwrap();
-            let mut vecdeque = tokens.to_vecdeque();
 
-            print!(
-                "{}",
-                match parser::File::parse(&config, &mut vecdeque) {
-                    Ok(ok) => serde_json::to_string_pretty(&ok).unwrap(),
-                    Err(err) => err.to_string(),
+        let indent = " ".repeat(config.body_indent());
+        return Ok(groups
+            .iter()
+            .map(|(title, todos)| {
+                todos
+                    .iter()
+                    .fold(format!("{title}\n"), |buf, (heading, todo)| {
+                        format!("{buf}{indent}{}  ({heading})\n", todo.print(config))
+                    })
+            })
+            .collect::<Vec<_>>()
+            .join("\n"));
+    }
+
+    if args.flat {
+        return Ok(file
+            .headings()
+            .iter()
+            .flat_map(|heading| heading.todos())
+            .map(|todo| todo.print(config) + "\n")
+            .collect());
+    }
+
+    if let Some(laid_out) = args
+        .columns
+        .filter(|columns| *columns > 1)
+        .and_then(|columns| in_columns(config, &file, columns))
+    {
+        return Ok(laid_out);
+    }
+
+    Ok(file.print(config))
+}
+
+/// Puts the headings named by an ISO date (`2024-03-01`) in chronological
+/// order among their own positions, leaving the other headings where they
+/// are.
+fn sort_dated_headings(file: &mut parser::File) {
+    let mut dated = vec![];
+    let slots = std::mem::take(file.headings_mut())
+        .into_iter()
+        .map(
+            |heading| match NaiveDate::parse_from_str(heading.name().trim(), "%Y-%m-%d") {
+                Ok(date) => {
+                    dated.push((date, heading));
+                    None
                 }
-            );
+                Err(_) => Some(heading),
+            },
+        )
+        .collect::<Vec<_>>();
+    dated.sort_by_key(|(date, _)| *date);
+
+    let mut dated = dated.into_iter().map(|(_, heading)| heading);
+    *file.headings_mut() = slots
+        .into_iter()
+        .map(|slot| slot.unwrap_or_else(|| dated.next().unwrap()))
+        .collect();
+}
+
+/// The summed up `~DURATION` estimates of the open todos, per heading and in
+/// total. Estimates that don't parse are warned about and left out.
+fn budget(config: &Config, file: &parser::File) -> String {
+    let mut sums = vec![];
+    for heading in file.headings() {
+        let mut sum = std::time::Duration::ZERO;
+        for todo in heading.todos() {
+            if config.is_done(&todo.state) {
+                continue;
+            }
+            match todo.description.estimate() {
+                Ok(estimate) => sum = sum.saturating_add(estimate.unwrap_or_default()),
+                Err(err) => eprintln!("Warning: {err} in \"{}\"", todo.print(config)),
+            }
         }
-        Command::EwwShow if exists => {
-            let tokens: Tokens = std::fs::read_to_string(&file).unwrap().parse().unwrap();
-            let mut vecdeque = tokens.to_vecdeque();
+        sums.push((heading.name(), sum));
+    }
+    let total = sums
+        .iter()
+        .fold(std::time::Duration::ZERO, |total, (_, sum)| {
+            total.saturating_add(*sum)
+        });
+    sums.push(("Total", total));
 
-            println!(
-                "{}",
-                match parser::File::parse(&config, &mut vecdeque) {
-                    Ok(ok) => serde_json::to_string_pretty(&file_format::eww::EwwTodo::from_todos(
-                        ok.headings()
-                            .into_iter()
-                            .flat_map(|heading| heading.todos())
-                            .collect(),
-                        &config
-                    ))
-                    .unwrap(),
-                    Err(err) => err.to_string(),
-                }
+    let format = |duration: std::time::Duration| {
+        let minutes = duration.as_secs() / 60;
+        match (minutes / 60, minutes % 60) {
+            (0, minutes) => format!("{minutes}m"),
+            (hours, 0) => format!("{hours}h"),
+            (hours, minutes) => format!("{hours}h {minutes:>2}m"),
+        }
+    };
+    let name_width = sums
+        .iter()
+        .map(|(name, _)| name.chars().count())
+        .max()
+        .unwrap_or(0);
+    let sum_width = sums
+        .iter()
+        .map(|(_, sum)| format(*sum).len())
+        .max()
+        .unwrap_or(0);
+
+    sums.into_iter()
+        .map(|(name, sum)| format!("{name:<name_width$}  {:>sum_width$}\n", format(sum)))
+        .collect()
+}
+
+/// The `>after:ID` dependencies between todos as a Graphviz digraph, with an
+/// edge from each todo to the ones waiting for it, along with the cycles in
+/// it.
+fn dependency_graph(config: &Config, file: &parser::File) -> (String, Vec<Vec<String>>) {
+    let todos = file
+        .headings()
+        .iter()
+        .flat_map(|heading| heading.todos())
+        .enumerate()
+        .filter(|(_, todo)| todo.anchor.is_some() || !todo.depends_on.is_empty())
+        .map(|(i, todo)| {
+            (
+                todo.anchor.clone().unwrap_or(format!("todo{}", i + 1)),
+                todo,
             )
+        })
+        .collect::<Vec<_>>();
+    let quote = |str: &str| format!("\"{}\"", str.replace('\\', "\\\\").replace('"', "\\\""));
+
+    let mut dot = String::from("digraph todos {\n");
+    let mut edges = BTreeMap::<&str, Vec<&str>>::new();
+    for (id, todo) in &todos {
+        dot += &format!(
+            "    {} [label={}];\n",
+            quote(id),
+            quote(&todo.to_source(config))
+        );
+        for dependency in &todo.depends_on {
+            if !todos.iter().any(|(other, _)| other == dependency) {
+                eprintln!("Warning: {id} is after {dependency}, which no todo is anchored as");
+                continue;
+            }
+            dot += &format!("    {} -> {};\n", quote(dependency), quote(id));
+            edges.entry(dependency).or_default().push(id);
         }
-        Command::Config => {
-            let config = Config::get().unwrap();
-            println!("{}", serde_json::to_string_pretty(&config).unwrap());
+    }
+    dot += "}\n";
+
+    // depth-first, a todo that's reached again while still on the path
+    // closes a cycle
+    fn visit<'a>(
+        id: &'a str,
+        edges: &BTreeMap<&'a str, Vec<&'a str>>,
+        path: &mut Vec<&'a str>,
+        done: &mut Vec<&'a str>,
+        cycles: &mut Vec<Vec<String>>,
+    ) {
+        if let Some(start) = path.iter().position(|other| *other == id) {
+            let mut cycle = path[start..]
+                .iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<_>>();
+            cycle.push(id.to_owned());
+            cycles.push(cycle);
+            return;
+        }
+        if done.contains(&id) {
+            return;
+        }
+
+        path.push(id);
+        for next in edges.get(id).into_iter().flatten() {
+            visit(next, edges, path, done, cycles);
+        }
+        path.pop();
+        done.push(id);
+    }
+
+    let mut cycles = vec![];
+    let mut done = vec![];
+    for (id, _) in &todos {
+        visit(id, &edges, &mut vec![], &mut done, &mut cycles);
+    }
+
+    (dot, cycles)
+}
+
+/// Lays the headings out side by side in `columns` columns of about the same
+/// height, keeping their order. `None` when they don't fit the wrap width.
+fn in_columns(config: &Config, file: &parser::File, columns: usize) -> Option<String> {
+    const GAP: usize = 2;
+
+    let separator = if config.compact() { 0 } else { 1 };
+    let headings = file
+        .headings()
+        .iter()
+        .map(|heading| heading.print(config))
+        .collect::<Vec<_>>();
+    let total = headings
+        .iter()
+        .map(|heading| heading.lines().count() + separator)
+        .sum::<usize>();
+    let target = total.div_ceil(columns);
+
+    let mut laid_out: Vec<Vec<&str>> = vec![vec![]];
+    for heading in &headings {
+        let column = laid_out.last_mut().unwrap();
+        if !column.is_empty()
+            && column.len() + heading.lines().count() > target
+            && laid_out.len() < columns
+        {
+            laid_out.push(vec![]);
+        }
+
+        let column = laid_out.last_mut().unwrap();
+        if !column.is_empty() && separator == 1 {
+            column.push("");
+        }
+        column.extend(heading.lines());
+    }
+
+    let widths = laid_out
+        .iter()
+        .map(|column| {
+            column
+                .iter()
+                .map(|line| textwrap::core::display_width(line))
+                .max()
+                .unwrap_or(0)
+        })
+        .collect::<Vec<_>>();
+    if let Some(width) = config.wrap_width() {
+        if widths.iter().sum::<usize>() + GAP * (widths.len() - 1) > width {
+            return None;
+        }
+    }
+
+    let height = laid_out.iter().map(Vec::len).max().unwrap_or(0);
+    Some(
+        (0..height)
+            .map(|row| {
+                let mut line = String::new();
+                for (column, width) in laid_out.iter().zip(&widths) {
+                    let cell = column.get(row).copied().unwrap_or("");
+                    line += cell;
+                    line += &" ".repeat(width - textwrap::core::display_width(cell) + GAP);
+                }
+                line.trim_end().to_owned() + "\n"
+            })
+            .collect(),
+    )
+}
+
+/// Reads a file as text, refusing ones over `max_file_bytes` or that aren't
+/// UTF-8.
+fn read_file(config: &Config, file: &Path) -> Result<String, String> {
+    let display = file.display();
+    log::debug!("reading {display}");
+    let size = std::fs::metadata(file)
+        .map_err(|err| format!("{display}: {err}"))?
+        .len();
+    if size > config.max_file_bytes() {
+        return Err(format!(
+            "{display} is {size} bytes, over the max_file_bytes limit of {}",
+            config.max_file_bytes()
+        ));
+    }
+
+    let bytes = std::fs::read(file).map_err(|err| format!("{display}: {err}"))?;
+    if bytes.starts_with(&GZIP_MAGIC) {
+        log::debug!("decompressing {display}");
+    }
+
+    contents(config, bytes).map_err(|err| format!("{display} {err}"))
+}
+
+/// What gzip compressed files start with.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// `bytes` compressed for a `.gz` file.
+fn gzip(bytes: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(vec![], Compression::default());
+    // writing to memory can't fail
+    encoder.write_all(bytes).unwrap();
+    encoder.finish().unwrap()
+}
+
+/// The text of a file's `bytes`, decompressed first if they're gzipped.
+/// Decompressing stops past `max_file_bytes`, so a small file can't expand
+/// into more than that.
+fn contents(config: &Config, bytes: Vec<u8>) -> Result<String, String> {
+    let bytes = if bytes.starts_with(&GZIP_MAGIC) {
+        let max = config.max_file_bytes();
+        let mut decompressed = vec![];
+        GzDecoder::new(bytes.as_slice())
+            .take(max.saturating_add(1))
+            .read_to_end(&mut decompressed)
+            .map_err(|err| format!("can't be decompressed: {err}"))?;
+        if decompressed.len() as u64 > max {
+            return Err(format!(
+                "decompresses to over the max_file_bytes limit of {max} bytes"
+            ));
+        }
+
+        decompressed
+    } else {
+        bytes
+    };
+
+    String::from_utf8(bytes).map_err(|_| "isn't valid UTF-8".to_owned())
+}
+
+/// Whether `path` is a todo file, compressed (`.todo.gz`) or not.
+fn is_todo_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.ends_with(".todo") || name.ends_with(".todo.gz"))
+}
+
+/// A new heading named `name`, starting out with its `heading_templates`
+/// entry.
+fn new_heading(config: &Config, name: &str) -> Result<parser::Heading, String> {
+    let intro = config
+        .heading_templates
+        .get(name)
+        .map_or(String::new(), |intro| format!("{}\n", intro.trim_end()));
+    let new: parser::File = parse_source(config, &format!("# {name}\n{intro}"))?;
+
+    Ok(new.into_headings().remove(0))
+}
+
+fn to_json<T: serde::Serialize>(value: &T, minified: bool) -> String {
+    if minified {
+        serde_json::to_string(value).unwrap()
+    } else {
+        serde_json::to_string_pretty(value).unwrap()
+    }
+}
+
+fn parse_file(config: &Config, file: &Path) -> Result<parser::File, String> {
+    parse_source(config, &read_file(config, file)?)
+}
+
+fn parse_source<T: Parse>(config: &Config, source: &str) -> Result<T, String> {
+    let tokens: Tokens = source
+        .parse()
+        .map_err(|err: TokenizeError| err.to_string())?;
+
+    T::parse(config, &mut tokens.to_vecdeque()).map_err(|err| err.to_string())
+}
+
+/// The file for `date`, `DDMMYYYY.todo` or the compressed `DDMMYYYY.todo.gz`
+/// when only that one exists.
+fn day_file(config: &Config, date: NaiveDate) -> PathBuf {
+    let name = date.format("%d%m%Y.todo").to_string();
+    let plain = config.resolve(name.as_ref());
+    if plain.exists() {
+        return plain;
+    }
+
+    let compressed = config.resolve(format!("{name}.gz").as_ref());
+    if compressed.exists() {
+        compressed
+    } else {
+        plain
+    }
+}
+
+/// The day a day file (`DDMMYYYY.todo`) is for.
+fn file_date(file: &Path) -> Option<NaiveDate> {
+    let name = file.file_name()?.to_str()?;
+    let stem = name
+        .strip_suffix(".gz")
+        .unwrap_or(name)
+        .strip_suffix(".todo")?;
+
+    NaiveDate::parse_from_str(stem, "%d%m%Y").ok()
+}
+
+/// Every `.todo` file across the configured directories, paired with a name
+/// that's unique across them: the file name, prefixed by its directory's name
+/// when there's more than one directory.
+fn vault_files(config: &Config) -> Vec<(String, PathBuf)> {
+    let tagged = config.directories.len() > 1;
+
+    config
+        .directories
+        .iter()
+        .flat_map(|directory| {
+            let vault = directory.file_name().unwrap_or_default().to_string_lossy();
+            let mut files = std::fs::read_dir(directory)
+                .map(|entries| {
+                    entries
+                        .filter_map(|entry| entry.ok())
+                        .map(|entry| entry.path())
+                        .filter(|path| is_todo_file(path))
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default();
+            files.sort();
+
+            files
+                .into_iter()
+                .map(|path| {
+                    let name = path.file_name().unwrap().to_string_lossy();
+                    let name = if tagged {
+                        format!("{vault}/{name}")
+                    } else {
+                        name.to_string()
+                    };
+
+                    (name, path)
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// Builds a command out of a shell-quoted line such as `code --wait`.
+fn shell_command(line: &str) -> Option<std::process::Command> {
+    let mut words = shlex::split(line)?.into_iter();
+    let mut command = std::process::Command::new(words.next()?);
+    command.args(words);
+
+    Some(command)
+}
+
+/// Runs the `post_write` hook with the file that changed, reporting but
+/// otherwise ignoring failures.
+fn post_write(config: &Config, file: &Path) {
+    let Some(hook) = &config.post_write else {
+        return;
+    };
+    let Some(mut command) = shell_command(hook) else {
+        eprintln!("Invalid post_write command: {hook}");
+        return;
+    };
+
+    match command.arg(file).output() {
+        Ok(output) if output.status.success() => {}
+        Ok(output) => eprintln!(
+            "post_write failed ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        ),
+        Err(err) => eprintln!("post_write failed: {err}"),
+    }
+}
+
+/// Whether `program` is a path to a file or the name of one on `$PATH`.
+fn on_path(program: &str) -> bool {
+    if program.contains('/') {
+        return Path::new(program).is_file();
+    }
+
+    std::env::var_os("PATH").is_some_and(|paths| {
+        std::env::split_paths(&paths).any(|directory| directory.join(program).is_file())
+    })
+}
+
+/// Whether `directory` is one, or could be made when a file is first written
+/// to it: the nearest part of the path that exists is a directory that can be
+/// written to.
+fn creatable_dir(directory: &Path) -> Result<(), &'static str> {
+    if directory.is_dir() {
+        return Ok(());
+    }
+    if directory.exists() {
+        return Err("not a directory");
+    }
+
+    let ancestor = directory
+        .ancestors()
+        .skip(1)
+        .map(|ancestor| {
+            if ancestor.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                ancestor
+            }
+        })
+        .find(|ancestor| ancestor.exists())
+        .ok_or("no such directory")?;
+    if !ancestor.is_dir() {
+        return Err("not a directory");
+    }
+
+    // tried rather than read off the permissions, which don't account for
+    // who's running or read-only mounts
+    let probe = ancestor.join(format!(".todo-validate.{}", std::process::id()));
+    std::fs::create_dir(&probe).map_err(|_| "can't be created")?;
+    let _ = std::fs::remove_dir(&probe);
+
+    Ok(())
+}
+
+/// Checks that the paths and commands in the config point at something,
+/// printing a line per check. Returns whether all of them passed.
+fn validate_config(config: &Config) -> bool {
+    let mut checks = vec![];
+
+    for directory in &config.directories {
+        checks.push((
+            format!("directory {}", directory.display()),
+            creatable_dir(directory),
+        ));
+    }
+    if config.template_fn.is_some() {
+        checks.push(("template function".to_owned(), Ok(())));
+    } else if let Some(template) = &config.template {
+        checks.push((
+            format!("template {}", template.display()),
+            if template.is_file() {
+                Ok(())
+            } else {
+                Err("no such file")
+            },
+        ));
+    }
+    if let Some(templates_dir) = &config.templates_dir {
+        checks.push((
+            format!("templates_dir {}", templates_dir.display()),
+            if templates_dir.is_dir() {
+                Ok(())
+            } else {
+                Err("not a directory")
+            },
+        ));
+    }
+    for (key, command) in [
+        ("editor", &config.editor),
+        ("post_write", &config.post_write),
+    ] {
+        let Some(command) = command else {
+            continue;
+        };
+        checks.push((
+            format!("{key} {command}"),
+            match shell_command(command) {
+                Some(command) if on_path(&command.get_program().to_string_lossy()) => Ok(()),
+                Some(_) => Err("not found on $PATH"),
+                None => Err("invalid command"),
+            },
+        ));
+    }
+
+    let mut valid = true;
+    for (check, result) in checks {
+        match result {
+            Ok(()) => println!("ok    {check}"),
+            Err(err) => {
+                println!("fail  {check}: {err}");
+                valid = false;
+            }
+        }
+    }
+
+    valid
+}
+
+/// The todo counts of `file`, from the cache when it's up to date, otherwise
+/// parsed and cached again.
+fn summary(config: &Config, file: &Path) -> Result<Summary, String> {
+    if let Some(summary) = Summary::load(file) {
+        return Ok(summary);
+    }
+
+    let modified = cache::modified(file).ok_or(format!("Can't read {}", file.display()))?;
+    let summary = Summary::new(config, &parse_file(config, file)?, modified);
+    summary.store(file);
+
+    Ok(summary)
+}
+
+/// Prints the file the way `show` does, or the empty placeholder when there's
+/// nothing to show.
+fn render_file(config: &Config, mut parsed: parser::File, args: &ShowArgs) -> Result<(), String> {
+    if args.numbers {
+        parsed.number_todos();
+    }
+    let parsed = transform(config, parsed)?;
+    if args.front_matter && !parsed.front_matter().is_empty() {
+        for (key, value) in parsed.front_matter() {
+            println!("{key}: {value}");
         }
-        _ => eprintln!("File doesn't exist"),
+        println!();
+    }
+
+    let shown = show(config, parsed, args)?;
+    if shown.is_empty() && !config.empty_placeholder().is_empty() {
+        println!("{}", config.empty_placeholder())
+    } else {
+        print!("{shown}")
+    }
+
+    Ok(())
+}
+
+/// Runs the file through the config's `transform` function before it's
+/// rendered, if there is one.
+fn transform(config: &Config, file: parser::File) -> Result<parser::File, String> {
+    let Some(transform) = &config.transform else {
+        return Ok(file);
+    };
+
+    transform
+        .transform(&file)
+        .map_err(|err| format!("transform failed: {err}"))
+}
+
+/// The lines `count` prints: one per state, most common first, then the
+/// total. With `bar_room` each state gets a bar scaled to fit that many
+/// columns.
+fn count_lines(summary: &Summary, bar_room: Option<usize>) -> Vec<String> {
+    let mut counts = summary.states.iter().collect::<Vec<_>>();
+    counts.sort_by(|(_, a), (_, b)| b.cmp(a));
+
+    let state_width = counts
+        .iter()
+        .map(|(state, _)| state.chars().count())
+        .chain(["total".len()])
+        .max()
+        .unwrap_or(0);
+    let count_width = summary.total.to_string().len();
+    let max = counts.first().map_or(0, |(_, count)| **count);
+
+    let mut lines = counts
+        .into_iter()
+        .map(|(state, count)| match bar_room {
+            Some(room) => {
+                let bar_width = room.saturating_sub(state_width + count_width + 3).min(max);
+                format!(
+                    "{state:<state_width$}  {count:>count_width$} {}",
+                    "#".repeat(count * bar_width / max)
+                )
+            }
+            None => format!("{state:<state_width$}  {count:>count_width$}"),
+        })
+        .collect::<Vec<_>>();
+    lines.push(format!(
+        "{:<state_width$}  {:>count_width$}",
+        "total", summary.total
+    ));
+
+    lines
+}
+
+fn write_file(config: &Config, file: &Path, contents: impl AsRef<[u8]>) {
+    log::debug!(
+        "writing {} bytes to {}",
+        contents.as_ref().len(),
+        file.display()
+    );
+    history::save(config, file);
+    if file.extension().is_some_and(|ext| ext == "gz") {
+        atomic::write(file, gzip(contents.as_ref())).unwrap();
+    } else {
+        atomic::write(file, contents).unwrap();
+    }
+    // warms the cache for the status bar commands
+    let _ = summary(config, file);
+    post_write(config, file);
+}
+
+/// Editors known to open at a line given as `+LINE`.
+const LINE_EDITORS: &[&str] = &[
+    "vi",
+    "vim",
+    "nvim",
+    "gvim",
+    "nano",
+    "emacs",
+    "emacsclient",
+    "micro",
+    "kak",
+    "joe",
+    "ne",
+];
+
+fn open_editor(config: &Config, file: &Path, line: Option<usize>) {
+    history::save(config, file);
+    if let Some(editor) = &config.editor {
+        let Some(mut command) = shell_command(editor) else {
+            eprintln!("Invalid editor command: {editor}");
+            return;
+        };
+
+        // other editors would take `+LINE` for a file to open
+        if let Some(line) = line.filter(|_| {
+            Path::new(command.get_program())
+                .file_name()
+                .is_some_and(|name| LINE_EDITORS.iter().any(|editor| name == *editor))
+        }) {
+            command.arg(format!("+{line}"));
+        }
+
+        command
+            .arg(file)
+            .stdin(Stdio::inherit())
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit())
+            .output()
+            .unwrap();
+    } else {
+        edit::edit_file(file).unwrap();
+    }
+    let _ = summary(config, file);
+    post_write(config, file);
+}
+
+/// Opens the editor, and again for as long as the file doesn't parse and
+/// the user wants to fix it.
+fn edit_until_valid(config: &Config, file: &Path, line: Option<usize>) {
+    open_editor(config, file, line);
+
+    while let Err(err) = parse_file(config, file) {
+        eprintln!("{err}");
+        if !confirm("Reopen the editor?", false) {
+            break;
+        }
+        open_editor(config, file, None);
+    }
+}
+
+fn template(
+    config: &Config,
+    date: Option<NaiveDate>,
+    pick: bool,
+    vars: &[(String, String)],
+) -> String {
+    let mut known = BTreeMap::new();
+    if let Some(date) = date {
+        known.insert("date".to_owned(), date.format("%Y-%m-%d").to_string());
+        known.insert("weekday".to_owned(), date.format("%A").to_string());
+        known.insert("week".to_owned(), date.format("%V").to_string());
+    }
+    known.extend(vars.iter().cloned());
+
+    if pick {
+        if let Some(template) = pick_template(config) {
+            return substitute(&std::fs::read_to_string(template).unwrap(), &known);
+        }
+    }
+
+    let Some(date) = date else {
+        return "".to_string();
+    };
+    if let Some(template_fn) = &config.template_fn {
+        match template_fn.render(date) {
+            Ok(rendered) => substitute(&rendered, &known),
+            Err(err) => {
+                eprintln!("Template function failed: {err}");
+                "".to_string()
+            }
+        }
+    } else if let Some(template) = &config.template {
+        substitute(&std::fs::read_to_string(template).unwrap(), &known)
+    } else {
+        "".to_string()
+    }
+}
+
+/// Fills in the `{{name}}` placeholders of a template, from a file or the
+/// template function. Unknown ones are left as they are, with a warning.
+fn substitute(template: &str, vars: &BTreeMap<String, String>) -> String {
+    let placeholder = Regex::new(r"\{\{\s*([\w-]+)\s*\}\}").unwrap();
+    let mut unknown = vec![];
+
+    let filled = placeholder.replace_all(template, |captures: &regex::Captures| {
+        match vars.get(&captures[1]) {
+            Some(value) => value.to_owned(),
+            None => {
+                if !unknown.contains(&captures[1].to_owned()) {
+                    unknown.push(captures[1].to_owned());
+                }
+                captures[0].to_owned()
+            }
+        }
+    });
+    for name in unknown {
+        eprintln!("Warning: unknown template variable \"{name}\"");
+    }
+
+    filled.into_owned()
+}
+
+/// Parses a `--template-var` `KEY=VALUE` pair.
+fn template_var(var: &str) -> Result<(String, String), String> {
+    var.split_once('=')
+        .map(|(key, value)| (key.trim().to_owned(), value.to_owned()))
+        .ok_or_else(|| format!("expected KEY=VALUE, got \"{var}\""))
+}
+
+/// Asks a yes/no question, going with `default` on an empty answer or when
+/// stdin is closed.
+fn confirm(question: &str, default: bool) -> bool {
+    print!("{question} [{}] ", if default { "Y/n" } else { "y/N" });
+    std::io::stdout().flush().unwrap();
+
+    let mut line = String::new();
+    if std::io::stdin().lock().read_line(&mut line).unwrap() == 0 {
+        println!();
+        return default;
+    }
+    match line.trim().to_lowercase().as_str() {
+        "y" | "yes" => true,
+        "n" | "no" => false,
+        _ => default,
+    }
+}
+
+/// Levenshtein distance between `a` and `b`, counted in chars.
+fn distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+
+    for (i, a) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, b) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(a != *b);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+
+    row[b.len()]
+}
+
+/// Index of the heading `name` refers to. Case-insensitive exact matches are
+/// taken as is, otherwise the closest heading within a few edits is offered.
+/// `None` when there's no such heading and it should be created.
+fn resolve_heading(file: &parser::File, name: &str) -> Option<usize> {
+    let lowercase = name.to_lowercase();
+    let headings = file.headings();
+
+    if let Some(index) = headings
+        .iter()
+        .position(|heading| heading.name().to_lowercase() == lowercase)
+    {
+        return Some(index);
+    }
+
+    let (index, closest) = headings
+        .iter()
+        .enumerate()
+        .map(|(index, heading)| (index, distance(&heading.name().to_lowercase(), &lowercase)))
+        .min_by_key(|(_, distance)| *distance)?;
+    if closest <= (lowercase.chars().count() / 3).max(1)
+        && confirm(
+            &format!("Use heading \"{}\"?", headings[index].name()),
+            false,
+        )
+    {
+        Some(index)
+    } else {
+        None
+    }
+}
+
+fn pick_template(config: &Config) -> Option<PathBuf> {
+    let Some(dir) = &config.templates_dir else {
+        eprintln!("No templates_dir configured, using the default template");
+        return None;
+    };
+    let mut templates = std::fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| path.extension().is_some_and(|ext| ext == "todo"))
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
+    if templates.is_empty() {
+        eprintln!(
+            "No templates found in {}, using the default template",
+            dir.display()
+        );
+        return None;
+    }
+    templates.sort();
+
+    for (i, template) in templates.iter().enumerate() {
+        println!(
+            "{}: {}",
+            i + 1,
+            template.file_stem().unwrap_or_default().to_string_lossy()
+        );
+    }
+
+    let mut stdin = std::io::stdin().lock();
+    loop {
+        print!("Template: ");
+        std::io::stdout().flush().unwrap();
+
+        let mut line = String::new();
+        if stdin.read_line(&mut line).unwrap() == 0 {
+            eprintln!("No template picked, using the default template");
+            return None;
+        }
+
+        match line.trim().parse::<usize>() {
+            Ok(i) if (1..=templates.len()).contains(&i) => {
+                return Some(templates.swap_remove(i - 1))
+            }
+            _ => eprintln!("Pick a number between 1 and {}", templates.len()),
+        }
+    }
+}
+
+/// Writes the starter config and creates the directory it names, refusing
+/// to replace a config that's already there unless forced.
+fn init(force: bool) {
+    let config_path = Config::path();
+    if config_path.exists() && !force {
+        eprintln!(
+            "{} already exists, use --force to overwrite",
+            config_path.display()
+        );
+        std::process::exit(1);
+    }
+    if let Some(parent) = config_path.parent() {
+        std::fs::create_dir_all(parent).unwrap();
+    }
+    atomic::write(&config_path, config::STARTER_CONFIG).unwrap();
+
+    let config = Config::get().unwrap();
+    std::fs::create_dir_all(config.directory()).unwrap();
+    println!("Config:    {}", config_path.display());
+    println!("Directory: {}", config.directory().display());
+}
+
+fn main() {
+    // silent unless asked for through `RUST_LOG`
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("off")).init();
+    let arg = Args::parse();
+    // before loading the config, which would write a default one
+    if let Command::Init { force } = arg.command {
+        init(force);
+        return;
+    }
+    let mut config = Config::get().unwrap();
+    config.colored = Some(arg.color.enabled());
+    let (files, day) = if let Some(day) = arg.day {
+        (vec![day_file(&config, day.date())], true)
+    } else if !arg.file.is_empty() {
+        (
+            arg.file
+                .iter()
+                .map(|file_| {
+                    let mut file = PathBuf::from(shellexpand::tilde(file_).as_ref());
+                    if file.extension().is_none() {
+                        file.set_extension("todo");
+                    }
+                    config.resolve(&file)
+                })
+                .collect::<Vec<_>>(),
+            false,
+        )
+    } else {
+        return;
+    };
+    if !arg.command.per_file() && files.len() > 1 {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--file can only be given once for this command",
+            )
+            .exit();
+    }
+
+    // a file that fails doesn't stop the rest, but still fails the run
+    let mut failed = false;
+    for (i, file) in files.iter().enumerate() {
+        if files.len() > 1 {
+            if i > 0 {
+                println!();
+            }
+            if config.colored() {
+                println!("\x1b[1m{}\x1b[22m", file.display());
+            } else {
+                println!("{}", file.display());
+            }
+        }
+        if let Err(err) = run(&arg, &mut config, file, day) {
+            eprintln!("{err}");
+            failed = true;
+        }
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+/// Runs the command on one file.
+fn run(arg: &Args, config: &mut Config, file: &Path, day: bool) -> Result<(), String> {
+    let exists = file.exists();
+
+    match arg.command.clone() {
+        Command::New { .. } if day && exists && arg.day.is_some() => {
+            let day = arg.day.unwrap();
+            return Err(format!(
+                "Todo for {day} ({}) already exists",
+                config.date_label(day.date())
+            ));
+        }
+        Command::New {
+            editor: true,
+            pick,
+            vars,
+        } => {
+            atomic::write(
+                file,
+                template(config, arg.day.map(|day| day.date()), pick, &vars),
+            )
+            .unwrap();
+            edit_until_valid(config, file, None);
+        }
+        Command::New { pick, vars, .. } => {
+            write_file(
+                config,
+                file,
+                template(config, arg.day.map(|day| day.date()), pick, &vars),
+            );
+        }
+        Command::Edit {
+            heading: Some(heading),
+            ..
+        } if exists => {
+            let parsed = parse_file(config, file)?;
+            let index = resolve_heading(&parsed, &heading)
+                .ok_or(format!("No heading named \"{heading}\""))?;
+            edit_until_valid(config, file, Some(parsed.headings()[index].line()));
+        }
+        Command::Edit { line, .. } if exists => edit_until_valid(config, file, line),
+        Command::Show(args) if args.since.is_some() => {
+            args.apply(config);
+            let today = Local::now().date_naive();
+
+            let mut first = true;
+            for date in args
+                .since
+                .unwrap()
+                .iter_days()
+                .take_while(|date| *date <= today)
+            {
+                let path = day_file(config, date);
+                if !path.exists() {
+                    continue;
+                }
+                let parsed = match parse_file(config, &path) {
+                    Ok(parsed) => parsed,
+                    Err(err) => {
+                        eprintln!("Skipping {}:\n{err}", path.display());
+                        continue;
+                    }
+                };
+
+                if !first {
+                    println!();
+                }
+                first = false;
+                println!("{}", config.date_label(date));
+                render_file(config, parsed, &args)?;
+            }
+        }
+        Command::Show(args) if exists => {
+            args.apply(config);
+            render_file(config, parse_file(config, file)?, &args)?;
+        }
+        Command::Raw if exists => {
+            let parsed = transform(config, parse_file(config, file)?)?;
+            print!("{}", to_json(&parsed, arg.minified));
+        }
+        Command::EwwShow {
+            summary_only: true, ..
+        } if exists => {
+            let summary = summary(config, file)?;
+            println!(
+                "{}",
+                to_json(
+                    &file_format::eww::EwwSummary::new(summary.total, summary.done),
+                    arg.minified
+                )
+            )
+        }
+        Command::EwwShow { summary, .. } if exists => {
+            let parsed = transform(config, parse_file(config, file)?)?;
+            let todos = parsed
+                .headings()
+                .iter()
+                .flat_map(|heading| heading.todos())
+                .collect();
+
+            println!(
+                "{}",
+                if summary {
+                    to_json(
+                        &file_format::eww::EwwSummarized::from_todos(todos, config),
+                        arg.minified,
+                    )
+                } else {
+                    to_json(
+                        &file_format::eww::EwwTodo::from_todos(todos, config),
+                        arg.minified,
+                    )
+                }
+            )
+        }
+        Command::Add {
+            heading,
+            description,
+        } => {
+            let mut parsed = if exists {
+                parse_file(config, file)?
+            } else {
+                parser::File::from(vec![])
+            };
+            let todo: parser::Todo = parse_source(config, &format!("[ ] {description}\n"))?;
+
+            let index = match resolve_heading(&parsed, &heading) {
+                Some(index) => index,
+                None if !parsed.headings().is_empty()
+                    && !confirm(&format!("Create heading \"{heading}\"?"), true) =>
+                {
+                    return Ok(());
+                }
+                None => {
+                    parsed.headings_mut().push(new_heading(config, &heading)?);
+                    parsed.headings().len() - 1
+                }
+            };
+            println!("{}", todo.print(config));
+            parsed.headings_mut()[index].push_todo(todo);
+
+            write_file(config, file, parsed.to_source(config));
+        }
+        Command::Note { text } => {
+            let mut parsed = if exists {
+                parse_file(config, file)?
+            } else {
+                parser::File::from(vec![])
+            };
+
+            // escaped so a note starting with `-` or `[` stays text
+            let source = text
+                .lines()
+                .map(str::trim_end)
+                .filter(|line| !line.is_empty())
+                .map(|line| {
+                    if LINE_MARKERS.iter().any(|marker| line.starts_with(marker)) {
+                        format!("\\{line}")
+                    } else {
+                        line.to_owned()
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join("\n");
+            if source.is_empty() {
+                return Err("Empty note".to_owned());
+            }
+
+            let name = config.notes_heading();
+            let note: parser::File = parse_source(config, &format!("# {name}\n{source}\n"))?;
+
+            let lowercase = name.to_lowercase();
+            let index = match parsed
+                .headings()
+                .iter()
+                .position(|heading| heading.name().to_lowercase() == lowercase)
+            {
+                Some(index) => index,
+                None => {
+                    parsed.headings_mut().push(new_heading(config, name)?);
+                    parsed.headings().len() - 1
+                }
+            };
+            parsed.headings_mut()[index].append(note.into_headings().remove(0));
+
+            write_file(config, file, parsed.to_source(config));
+        }
+        Command::Complete { query, all } if exists => {
+            let mut parsed = parse_file(config, file)?;
+
+            let lowercase = query.to_lowercase();
+            let mut matches = parsed
+                .headings_mut()
+                .iter_mut()
+                .flat_map(|heading| heading.todos_mut())
+                .filter(|todo| {
+                    todo.description
+                        .print(config)
+                        .to_lowercase()
+                        .contains(&lowercase)
+                })
+                .collect::<Vec<_>>();
+
+            if matches.is_empty() {
+                return Err(format!("No todo matches \"{query}\""));
+            } else if matches.len() > 1 && !all {
+                let listed = matches
+                    .iter()
+                    .map(|todo| format!("\n    {}", todo.print(config)))
+                    .collect::<String>();
+                return Err(format!(
+                    "{} todos match \"{query}\", use --all or a more specific query:{listed}",
+                    matches.len()
+                ));
+            }
+
+            for todo in matches.iter_mut() {
+                todo.set_state(config, config.done_marker());
+                println!("{}", todo.print(config));
+            }
+
+            write_file(config, file, parsed.to_source(config));
+        }
+        Command::Toggle { index } if exists => {
+            let mut parsed = parse_file(config, file)?;
+
+            let Some(todo) = index.checked_sub(1).and_then(|index| {
+                parsed
+                    .headings_mut()
+                    .iter_mut()
+                    .flat_map(|heading| heading.todos_mut())
+                    .nth(index)
+            }) else {
+                return Err(format!("No todo at index {index}"));
+            };
+
+            todo.toggle(config);
+            println!("{}", todo.print(config));
+
+            write_file(config, file, parsed.to_source(config));
+        }
+        Command::Merge if exists => {
+            let mut parsed = parse_file(config, file)?;
+
+            match parsed.merge_headings() {
+                0 => println!("No duplicate headings"),
+                merged => {
+                    println!(
+                        "Merged {merged} heading{}",
+                        if merged == 1 { "" } else { "s" }
+                    );
+                    write_file(config, file, parsed.to_source(config));
+                }
+            }
+        }
+        Command::Replace {
+            from,
+            to,
+            regex,
+            dry_run,
+            all,
+        } if exists => {
+            let pattern = Regex::new(&if regex { from } else { regex::escape(&from) })
+                .map_err(|err| err.to_string())?;
+            let mut parsed = parse_file(config, file)?;
+
+            let mut changed = 0;
+            for heading in parsed.headings_mut() {
+                for text in heading.texts_mut() {
+                    let before = text.to_source(config);
+                    text.replace_normal(all, |str| {
+                        if regex {
+                            pattern.replace_all(str, to.as_str()).into_owned()
+                        } else {
+                            pattern.replace_all(str, NoExpand(&to)).into_owned()
+                        }
+                    });
+
+                    let after = text.to_source(config);
+                    if before != after {
+                        println!("- {before}\n+ {after}");
+                        changed += 1;
+                    }
+                }
+            }
+
+            if changed == 0 {
+                println!("Nothing to replace");
+            } else if !dry_run {
+                write_file(config, file, parsed.to_source(config));
+            }
+        }
+        Command::Prune {
+            older_than,
+            all,
+            vault,
+        } if vault || exists => {
+            let files = if vault {
+                vault_files(config)
+            } else {
+                vec![(file.display().to_string(), file.to_path_buf())]
+            };
+            let cutoff =
+                older_than.map(|days| Local::now().date_naive() - Duration::days(days.into()));
+
+            for (name, path) in files {
+                let mut parsed = match parse_file(config, &path) {
+                    Ok(ok) => ok,
+                    Err(err) => {
+                        eprintln!("Skipping {name}:\n{err}");
+                        continue;
+                    }
+                };
+
+                // a todo without a `^YYYY-MM-DD` stamp is as old as its day
+                // file, or else as the file's last change
+                let file_age = file_date(&path).or_else(|| {
+                    cache::modified(&path)
+                        .map(|modified| chrono::DateTime::<Local>::from(modified).date_naive())
+                });
+                let mut pruned = 0;
+                for heading in parsed.headings_mut() {
+                    let before = heading.todos().len();
+                    heading.retain_todos(|todo| {
+                        let old = all
+                            || todo
+                                .completed
+                                .or(file_age)
+                                .zip(cutoff)
+                                .is_some_and(|(date, cutoff)| date < cutoff);
+                        !(old && config.is_done(&todo.state))
+                    });
+                    pruned += before - heading.todos().len();
+                }
+
+                if pruned > 0 {
+                    println!(
+                        "{name}: pruned {pruned} done todo{}",
+                        if pruned == 1 { "" } else { "s" }
+                    );
+                    write_file(config, &path, parsed.to_source(config));
+                }
+            }
+        }
+        Command::Migrate { vault, dry_run } if vault || exists => {
+            let files = if vault {
+                vault_files(config)
+            } else {
+                vec![(file.display().to_string(), file.to_path_buf())]
+            };
+
+            let mut migrated = 0;
+            for (name, path) in files {
+                let source = match read_file(config, &path) {
+                    Ok(source) => source,
+                    Err(err) => {
+                        eprintln!("Skipping {name}: {err}");
+                        continue;
+                    }
+                };
+                let parsed: parser::File = match parse_source(config, &source) {
+                    Ok(parsed) => parsed,
+                    Err(err) => {
+                        eprintln!("Skipping {name}, it doesn't parse:\n{err}");
+                        continue;
+                    }
+                };
+
+                let canonical = parsed.to_source(config);
+                if canonical != source {
+                    println!("{name}");
+                    migrated += 1;
+                    if !dry_run {
+                        write_file(config, &path, canonical);
+                    }
+                }
+            }
+
+            if migrated == 0 {
+                println!("Nothing to migrate");
+            }
+        }
+        Command::Clean {
+            drop_done,
+            remove_empty_files,
+        } if exists => {
+            let mut parsed = parse_file(config, file)?;
+
+            let before = parsed.headings().len();
+            parsed.headings_mut().retain(|heading| {
+                let remove = heading.is_empty() || drop_done && heading.is_done(config);
+                if remove {
+                    println!("Removed heading {}", heading.name());
+                }
+                !remove
+            });
+
+            if remove_empty_files && parsed.headings().is_empty() {
+                history::save(config, file);
+                std::fs::remove_file(file).unwrap();
+                post_write(config, file);
+                println!("Removed {}", file.display());
+            } else if parsed.headings().len() != before {
+                write_file(config, file, parsed.to_source(config));
+            }
+        }
+        Command::Count { bar } if exists => {
+            let summary = summary(config, file)?;
+            for line in count_lines(&summary, bar.then(textwrap::termwidth)) {
+                println!("{line}");
+            }
+        }
+        Command::Undo => match history::undo(file) {
+            Some((before, after)) => {
+                let _ = summary(config, file);
+                post_write(config, file);
+
+                let contents = |bytes| {
+                    contents(config, bytes).map_err(|err| format!("{} {err}", file.display()))
+                };
+                let (before, after) = (contents(before)?, contents(after)?);
+                let before = before.lines().collect::<Vec<_>>();
+                let after = after.lines().collect::<Vec<_>>();
+                let prefix = before
+                    .iter()
+                    .zip(&after)
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                let suffix = before[prefix..]
+                    .iter()
+                    .rev()
+                    .zip(after[prefix..].iter().rev())
+                    .take_while(|(a, b)| a == b)
+                    .count();
+
+                println!("Restored {}", file.display());
+                for line in &before[prefix..before.len() - suffix] {
+                    println!("- {line}");
+                }
+                for line in &after[prefix..after.len() - suffix] {
+                    println!("+ {line}");
+                }
+            }
+            None => return Err("Nothing to undo".to_owned()),
+        },
+        Command::Graph if exists => {
+            let parsed = parse_file(config, file)?;
+            let (dot, cycles) = dependency_graph(config, &parsed);
+
+            print!("{dot}");
+            for cycle in &cycles {
+                eprintln!("Cycle: {}", cycle.join(" -> "));
+            }
+            if !cycles.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        Command::Toc { jump } if exists => {
+            let parsed = parse_file(config, file)?;
+            let headings = parsed.headings();
+
+            if let Some(jump) = jump {
+                let heading = jump
+                    .checked_sub(1)
+                    .and_then(|i| headings.get(i))
+                    .ok_or(format!("No heading at index {jump}"))?;
+                edit_until_valid(config, file, Some(heading.line()));
+                return Ok(());
+            }
+
+            let index_width = headings.len().to_string().len();
+            let line_width = headings
+                .last()
+                .map_or(0, |heading| heading.line().to_string().len());
+            for (i, heading) in headings.iter().enumerate() {
+                let todos = heading.todos().len();
+                println!(
+                    "{:>index_width$}. {}  (line {:>line_width$}, {todos} todo{})",
+                    i + 1,
+                    heading.name(),
+                    heading.line(),
+                    if todos == 1 { "" } else { "s" }
+                );
+            }
+        }
+        Command::Watch { interval } if exists => {
+            let mut source = String::new();
+            let mut parsed: Option<parser::File> = None;
+            let mut failed: Option<String> = None;
+
+            loop {
+                let current = match read_file(config, file) {
+                    Ok(current) => current,
+                    Err(err) => {
+                        if failed.as_ref() != Some(&err) {
+                            println!("\x1b[2J\x1b[H{err}");
+                            std::io::stdout().flush().unwrap();
+                            failed = Some(err);
+                        }
+                        parsed = None;
+                        std::thread::sleep(std::time::Duration::from_millis(interval));
+                        continue;
+                    }
+                };
+                failed = None;
+
+                if current != source || parsed.is_none() {
+                    let result = match &mut parsed {
+                        Some(parsed) => {
+                            let old = source.split_inclusive('\n').collect::<Vec<_>>();
+                            let new = current.split_inclusive('\n').collect::<Vec<_>>();
+                            let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+                            let suffix = old[prefix..]
+                                .iter()
+                                .rev()
+                                .zip(new[prefix..].iter().rev())
+                                .take_while(|(a, b)| a == b)
+                                .count();
+
+                            parsed.reparse_range(
+                                config,
+                                &current,
+                                prefix + 1..new.len() - suffix + 1,
+                                old.len() - suffix - prefix,
+                            )
+                        }
+                        None => parse_file(config, file).map(|file| parsed = Some(file)),
+                    };
+                    source = current;
+
+                    print!("\x1b[2J\x1b[H");
+                    match (result, &parsed) {
+                        (Ok(()), Some(parsed)) => print!("{}", parsed.print(config)),
+                        (Err(err), _) => {
+                            print!("{err}");
+                            parsed = None;
+                        }
+                        _ => {}
+                    }
+                    std::io::stdout().flush().unwrap();
+                }
+
+                std::thread::sleep(std::time::Duration::from_millis(interval));
+            }
+        }
+        Command::Export {
+            format: Format::Jsonl,
+            output,
+        } => {
+            // streamed into a temporary file that's renamed over the output
+            // at the end
+            let mut writer: Box<dyn Write> = match &output {
+                Some(output) => Box::new(std::io::BufWriter::new(
+                    std::fs::File::create(atomic::temp_path(output)).unwrap(),
+                )),
+                None => Box::new(std::io::stdout().lock()),
+            };
+
+            for (name, path) in vault_files(config) {
+                let parsed = match parse_file(config, &path) {
+                    Ok(parsed) => parsed,
+                    Err(err) => {
+                        eprintln!("Skipping {name}:\n{err}");
+                        continue;
+                    }
+                };
+
+                for heading in parsed.headings() {
+                    for todo in heading.todos() {
+                        serde_json::to_writer(
+                            &mut writer,
+                            &TodoLine {
+                                file: &name,
+                                heading: heading.name(),
+                                state: todo.state.to_source(config).trim().to_owned(),
+                                text: todo.description.to_source(config),
+                                tags: &todo.contexts,
+                            },
+                        )
+                        .unwrap();
+                        writeln!(writer).unwrap();
+                    }
+                }
+            }
+
+            writer.flush().unwrap();
+            drop(writer);
+            if let Some(output) = output {
+                std::fs::rename(atomic::temp_path(&output), output).unwrap();
+            }
+        }
+        Command::Export { format, output } => {
+            let mut export = BTreeMap::new();
+
+            for (name, path) in vault_files(config) {
+                match parse_file(config, &path) {
+                    Ok(parsed) => {
+                        export.insert(name, parsed);
+                    }
+                    Err(err) => eprintln!("Skipping {name}:\n{err}"),
+                }
+            }
+
+            let rendered = match format {
+                Format::Json => to_json(&export, arg.minified),
+                Format::Eww => to_json(
+                    &export
+                        .iter()
+                        .map(|(name, parsed)| {
+                            (
+                                name,
+                                file_format::eww::EwwTodo::from_todos(
+                                    parsed
+                                        .headings()
+                                        .iter()
+                                        .flat_map(|heading| heading.todos())
+                                        .collect(),
+                                    config,
+                                ),
+                            )
+                        })
+                        .collect::<BTreeMap<_, _>>(),
+                    arg.minified,
+                ),
+                Format::Plain => export
+                    .iter()
+                    .map(|(name, parsed)| {
+                        format!(
+                            "{name}\n{}",
+                            textwrap::indent(&parsed.print(config), "    ")
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                // streamed line by line above
+                Format::Jsonl => unreachable!(),
+            };
+
+            match output {
+                Some(output) => atomic::write(&output, rendered + "\n").unwrap(),
+                None => println!("{rendered}"),
+            }
+        }
+        Command::Import { force, input, .. } => {
+            let json = match &input {
+                Some(input) => read_file(config, input)?,
+                None => {
+                    let mut json = String::new();
+                    std::io::stdin()
+                        .read_to_string(&mut json)
+                        .map_err(|err| format!("Can't read the import from stdin: {err}"))?;
+                    json
+                }
+            };
+
+            let import: BTreeMap<String, parser::File> =
+                serde_json::from_str(&json).map_err(|err| format!("Invalid import: {err}"))?;
+
+            for (name, parsed) in import {
+                let (directory, file_name) = match name.split_once('/') {
+                    Some((vault, file_name)) => (
+                        config
+                            .directories
+                            .iter()
+                            .find(|directory| directory.file_name().is_some_and(|dir| dir == vault))
+                            .map(PathBuf::as_path),
+                        file_name,
+                    ),
+                    None => (Some(config.directory()), name.as_str()),
+                };
+                let Some(directory) = directory else {
+                    eprintln!("Skipping {name}: no such directory configured");
+                    continue;
+                };
+                if Path::new(file_name).file_name() != Some(file_name.as_ref()) {
+                    eprintln!("Skipping {name}: not a plain file name");
+                    continue;
+                }
+
+                let path = directory.join(file_name);
+                if path.exists() && !force {
+                    eprintln!("Skipping {name}: already exists, use --force to overwrite");
+                    continue;
+                }
+
+                write_file(config, &path, parsed.to_source(config));
+                println!("Imported {name}");
+            }
+        }
+        Command::ImportTxt { force: false, .. } if exists => {
+            return Err("File already exists, use --force to overwrite".to_owned());
+        }
+        Command::ImportTxt { input, .. } => {
+            let source = read_file(config, &input)?;
+
+            let parsed = todo_txt::parse(config, &source)?;
+            write_file(config, file, parsed.to_source(config));
+        }
+        Command::Config { validate: true } => {
+            if !validate_config(config) {
+                std::process::exit(1);
+            }
+        }
+        Command::Config { .. } => {
+            let config = Config::get().unwrap();
+            println!("{}", to_json(&config, arg.minified));
+        }
+        _ => return Err("File doesn't exist".to_owned()),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(line: &str) -> Option<Vec<String>> {
+        let command = shell_command(line)?;
+        let mut words = vec![command.get_program().to_string_lossy().into_owned()];
+        words.extend(
+            command
+                .get_args()
+                .map(|arg| arg.to_string_lossy().into_owned()),
+        );
+
+        Some(words)
+    }
+
+    #[test]
+    fn editor_is_split_like_a_shell() {
+        assert_eq!(words("nvim").unwrap(), ["nvim"]);
+        assert_eq!(words("code --wait").unwrap(), ["code", "--wait"]);
+        assert_eq!(
+            words(r#""/opt/My Editor/bin/edit" -n --title 'a b'"#).unwrap(),
+            ["/opt/My Editor/bin/edit", "-n", "--title", "a b"]
+        );
+        assert_eq!(words(r"emacs\ client -c").unwrap(), ["emacs client", "-c"]);
+    }
+
+    #[test]
+    fn invalid_editor_is_rejected() {
+        assert!(words("").is_none());
+        assert!(words("   ").is_none());
+        assert!(words("vim 'unterminated").is_none());
+    }
+
+    fn count(source: &str) -> Vec<String> {
+        let config =
+            Config::load(r#"return { directory = "/tmp", todo_state = { x = "x" } }"#).unwrap();
+        let parsed = parse_source(&config, source).unwrap();
+
+        count_lines(
+            &Summary::new(&config, &parsed, std::time::SystemTime::UNIX_EPOCH),
+            None,
+        )
+    }
+
+    #[test]
+    fn count_always_ends_with_the_total() {
+        assert_eq!(count(""), ["total  0"]);
+        assert_eq!(count("# A\n"), ["total  0"]);
+        assert_eq!(
+            count("# A\n[ ] a\n[ ] b\n[x] c\n"),
+            ["[ ]    2", "[x]    1", "total  3"]
+        );
+    }
+
+    #[test]
+    fn missing_directory_is_fine_if_it_can_be_created() {
+        let temp = std::env::temp_dir();
+        assert_eq!(creatable_dir(&temp), Ok(()));
+        assert_eq!(
+            creatable_dir(
+                &temp
+                    .join(format!("todo-{}", std::process::id()))
+                    .join("vault")
+            ),
+            Ok(())
+        );
+        assert!(creatable_dir(Path::new("/proc/todo/vault")).is_err());
+    }
+
+    #[test]
+    fn template_function_output_is_substituted() {
+        let config = Config::load(
+            r##"return {
+                directory = "/tmp",
+                template = function(date) return "# {{weekday}} " .. date.day .. "\n[ ] ask {{who}}\n" end,
+            }"##,
+        )
+        .unwrap();
+
+        assert_eq!(
+            template(
+                &config,
+                NaiveDate::from_ymd_opt(2024, 1, 1),
+                false,
+                &[("who".to_owned(), "sam".to_owned())]
+            ),
+            "# Monday 1\n[ ] ask sam\n"
+        );
+    }
+
+    #[test]
+    fn gzipped_contents() {
+        let config =
+            Config::load(r#"return { directory = "/tmp", max_file_bytes = 1000 }"#).unwrap();
+
+        let source = "# A\n[ ] todo\n";
+        assert_eq!(
+            contents(&config, gzip(source.as_bytes())),
+            Ok(source.to_owned())
+        );
+        assert_eq!(contents(&config, source.into()), Ok(source.to_owned()));
+
+        let corrupt = contents(&config, vec![0x1f, 0x8b, 0, 0]);
+        assert!(corrupt.is_err_and(|err| err.starts_with("can't be decompressed")));
+
+        let bomb = contents(&config, gzip(&[b'a'; 1001]));
+        assert!(bomb.is_err_and(|err| err.contains("max_file_bytes")));
+        assert!(contents(&config, gzip(&[b'a'; 1000])).is_ok());
+    }
+
+    #[test]
+    fn budget_sums_open_todos() {
+        let config =
+            Config::load(r#"return { directory = "/tmp", todo_state = { x = "x" } }"#).unwrap();
+        let parsed = parse_source(
+            &config,
+            "# A\n[ ] a ~30m\n[ ] b ~1h15m\n[x] c ~2d\n\n# B\n[ ] d ~2d\n[ ] e ~nope\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            budget(&config, &parsed),
+            "A       1h 45m\nB          48h\nTotal  49h 45m\n"
+        );
+    }
+
+    fn show_with(flags: &[&str], source: &str) -> String {
+        let mut config =
+            Config::load(r#"return { directory = "/tmp", todo_state = { x = "x" } }"#).unwrap();
+        config.colored = Some(false);
+        let Command::Show(args) = Args::parse_from(["todo", "show"].iter().chain(flags)).command
+        else {
+            unreachable!()
+        };
+
+        show(&config, parse_source(&config, source).unwrap(), &args).unwrap()
+    }
+
+    #[test]
+    fn done_todos_are_collapsed_unless_shown() {
+        let source = "# A\n[ ] open\n[x] done\ntext\n";
+
+        assert_eq!(
+            show_with(&[], source),
+            "A\n    [ ] open\n    text\n    ▸ 1 done\n"
+        );
+        assert_eq!(
+            show_with(&["--show-done"], source),
+            "A\n    [ ] open\n    [x] done\n    text\n"
+        );
+        assert_eq!(show_with(&["--todos-only"], source), "A\n    [ ] open\n");
     }
 }